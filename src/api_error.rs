@@ -0,0 +1,147 @@
+use std::{fmt, time::Duration};
+
+use reqwest::StatusCode;
+
+/// API呼び出しで発生したエラーの分類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiErrorKind {
+    /// APIキーが無効、または権限がない
+    Auth,
+    /// レート制限に達した
+    RateLimit,
+    /// サーバーが過負荷状態
+    Overloaded,
+    /// 接続できない、またはレスポンスの途中で切断された
+    Network,
+    /// リクエストの内容が不正
+    BadRequest,
+    /// 上記以外
+    Other,
+}
+
+impl ApiErrorKind {
+    /// HTTPステータスコードからエラーの種類を判定する
+    pub fn from_status(status: StatusCode) -> Self {
+        match status.as_u16() {
+            401 | 403 => ApiErrorKind::Auth,
+            429 => ApiErrorKind::RateLimit,
+            // 529はAnthropicが過負荷時に返すステータス
+            503 | 529 => ApiErrorKind::Overloaded,
+            400 | 404 | 413 | 422 => ApiErrorKind::BadRequest,
+            _ => ApiErrorKind::Other,
+        }
+    }
+
+    /// 1回だけ送信するモードで終了するときの終了コード
+    ///
+    /// 2はclapが引数のエラーで使うので、それ以外の値を割り当てる。
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            ApiErrorKind::Other => 1,
+            ApiErrorKind::Auth => 3,
+            ApiErrorKind::RateLimit => 4,
+            ApiErrorKind::Overloaded => 5,
+            ApiErrorKind::Network => 6,
+            ApiErrorKind::BadRequest => 7,
+        }
+    }
+
+    /// anyhow::Errorの中身からエラーの種類を判定する
+    pub fn classify(err: &anyhow::Error) -> Self {
+        if let Some(api_error) = err.downcast_ref::<ApiError>() {
+            return api_error.kind;
+        }
+        if err.downcast_ref::<reqwest::Error>().is_some()
+            || err.downcast_ref::<std::io::Error>().is_some()
+        {
+            return ApiErrorKind::Network;
+        }
+        ApiErrorKind::Other
+    }
+}
+
+impl fmt::Display for ApiErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ApiErrorKind::Auth => write!(f, "認証エラー"),
+            ApiErrorKind::RateLimit => write!(f, "レート制限"),
+            ApiErrorKind::Overloaded => write!(f, "サーバー過負荷"),
+            ApiErrorKind::Network => write!(f, "通信エラー"),
+            ApiErrorKind::BadRequest => write!(f, "不正なリクエスト"),
+            ApiErrorKind::Other => write!(f, "エラー"),
+        }
+    }
+}
+
+/// APIが2xx以外のステータスを返した場合のエラー
+#[derive(Debug)]
+pub struct ApiError {
+    pub kind: ApiErrorKind,
+    pub status: StatusCode,
+    pub message: String,
+    /// `retry-after` やレート制限のヘッダーで指定された、再試行までの待ち時間
+    pub retry_after: Option<Duration>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, message: String) -> Self {
+        Self {
+            kind: ApiErrorKind::from_status(status),
+            status,
+            message,
+            retry_after: None,
+        }
+    }
+
+    pub fn with_retry_after(mut self, retry_after: Option<Duration>) -> Self {
+        self.retry_after = retry_after;
+        self
+    }
+
+    /// 時間をおいて同じリクエストを送り直せば成功する可能性があるか
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self.kind,
+            ApiErrorKind::RateLimit | ApiErrorKind::Overloaded
+        ) || self.status.is_server_error()
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({}): {}", self.kind, self.status, self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_status_codes() {
+        assert_eq!(
+            ApiErrorKind::from_status(StatusCode::UNAUTHORIZED),
+            ApiErrorKind::Auth
+        );
+        assert_eq!(
+            ApiErrorKind::from_status(StatusCode::TOO_MANY_REQUESTS),
+            ApiErrorKind::RateLimit
+        );
+        assert_eq!(
+            ApiErrorKind::from_status(StatusCode::from_u16(529).unwrap()),
+            ApiErrorKind::Overloaded
+        );
+        assert_eq!(
+            ApiErrorKind::from_status(StatusCode::BAD_REQUEST),
+            ApiErrorKind::BadRequest
+        );
+
+        let err = anyhow::Error::new(ApiError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "slow down".to_string(),
+        ));
+        assert_eq!(ApiErrorKind::classify(&err), ApiErrorKind::RateLimit);
+    }
+}
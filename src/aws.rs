@@ -0,0 +1,469 @@
+use std::{collections::BTreeMap, env, fs, path::PathBuf, time};
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, KeyInit, Mac};
+use reqwest::blocking::Client;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::process::run_command;
+
+// ECSのコンテナの認証情報のエンドポイント（`AWS_CONTAINER_CREDENTIALS_RELATIVE_URI` の前に付ける）
+const CONTAINER_ENDPOINT: &str = "http://169.254.170.2";
+
+// EC2のインスタンスメタデータサービスのエンドポイント
+const IMDS_ENDPOINT: &str = "http://169.254.169.254";
+
+// EC2の外ではインスタンスメタデータサービスに接続できないので、すぐに諦める
+const IMDS_TIMEOUT: time::Duration = time::Duration::from_secs(1);
+
+/// AWSの認証情報
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+    /// 一時的な認証情報の有効期限。期限のない認証情報では `None`
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+// `credential_process` が出力するJSON
+// https://docs.aws.amazon.com/cli/latest/userguide/cli-configure-sourcing-external.html
+//
+// ECSのコンテナやEC2のインスタンスメタデータサービスも同じ形で返すが、セッショントークンの名前は `Token` になる。
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct CredentialsResponse {
+    access_key_id: String,
+    secret_access_key: String,
+    #[serde(alias = "Token")]
+    session_token: Option<String>,
+    expiration: Option<DateTime<Utc>>,
+}
+
+impl From<CredentialsResponse> for Credentials {
+    fn from(response: CredentialsResponse) -> Self {
+        Self {
+            access_key_id: response.access_key_id,
+            secret_access_key: response.secret_access_key,
+            session_token: response.session_token,
+            expires_at: response.expiration,
+        }
+    }
+}
+
+impl Credentials {
+    /// AWS CLIと同じ順に認証情報を探す
+    ///
+    /// 1. 環境変数 `AWS_ACCESS_KEY_ID`、`AWS_SECRET_ACCESS_KEY`、`AWS_SESSION_TOKEN`
+    /// 2. `~/.aws/credentials` のプロファイル
+    /// 3. `~/.aws/config` のプロファイルの `credential_process`
+    /// 4. ECSのコンテナの認証情報（`AWS_CONTAINER_CREDENTIALS_RELATIVE_URI` など）
+    /// 5. EC2のインスタンスメタデータサービス（IMDSv2）
+    ///
+    /// プロファイルは `profile`、`AWS_PROFILE`、`default` の順に使う。
+    /// IAM Identity Center（SSO）、Web IDトークン、`role_arn` によるロールの引き受けには対応していないので、
+    /// それらが設定されている場合は、`credential_process` の設定を促すエラーを返す。
+    pub fn load(profile: Option<&str>) -> Result<Self> {
+        if let Some(credentials) = Self::from_env() {
+            return Ok(credentials);
+        }
+
+        let profile = profile_name(profile);
+        if env::var_os("AWS_WEB_IDENTITY_TOKEN_FILE").is_some() {
+            return Err(unsupported(
+                "Web IDトークン（AWS_WEB_IDENTITY_TOKEN_FILE）",
+                &profile,
+            ));
+        }
+        if let Some(section) = credentials_file().and_then(|path| read_section(&path, &profile)) {
+            if let (Some(access_key_id), Some(secret_access_key)) = (
+                section.get("aws_access_key_id"),
+                section.get("aws_secret_access_key"),
+            ) {
+                return Ok(Self {
+                    access_key_id: access_key_id.clone(),
+                    secret_access_key: secret_access_key.clone(),
+                    session_token: section.get("aws_session_token").cloned(),
+                    expires_at: None,
+                });
+            }
+        }
+
+        let config = config_file().and_then(|path| read_section(&path, &config_section(&profile)));
+        if let Some(config) = &config {
+            if let Some(command) = config.get("credential_process") {
+                let output = run_command(command).with_context(|| {
+                    format!("credential_process を実行できませんでした: {}", command)
+                })?;
+                let response: CredentialsResponse = serde_json::from_str(&output)
+                    .context("credential_process の出力を読み込めませんでした")?;
+                return Ok(response.into());
+            }
+            if config.contains_key("sso_session") || config.contains_key("sso_start_url") {
+                return Err(unsupported("IAM Identity Center（SSO）", &profile));
+            }
+            if config.contains_key("web_identity_token_file") {
+                return Err(unsupported(
+                    "Web IDトークン（web_identity_token_file）",
+                    &profile,
+                ));
+            }
+            if config.contains_key("role_arn") {
+                return Err(unsupported("ロールの引き受け（role_arn）", &profile));
+            }
+        }
+
+        if let Some(credentials) = Self::from_container()? {
+            return Ok(credentials);
+        }
+        if let Some(credentials) = Self::from_instance_metadata() {
+            return Ok(credentials);
+        }
+
+        Err(anyhow!(
+            "AWSの認証情報が見つかりません（プロファイル: {}）。環境変数 AWS_ACCESS_KEY_ID と AWS_SECRET_ACCESS_KEY をセットするか、~/.aws/credentials を設定してください",
+            profile
+        ))
+    }
+
+    // ECSのタスクロールの認証情報を取得する
+    // https://docs.aws.amazon.com/sdkref/latest/guide/feature-container-credentials.html
+    fn from_container() -> Result<Option<Self>> {
+        let var = |key: &str| env::var(key).ok().filter(|v| !v.is_empty());
+        let url = match (
+            var("AWS_CONTAINER_CREDENTIALS_RELATIVE_URI"),
+            var("AWS_CONTAINER_CREDENTIALS_FULL_URI"),
+        ) {
+            (Some(relative), _) => format!("{}{}", CONTAINER_ENDPOINT, relative),
+            (None, Some(full)) => full,
+            (None, None) => return Ok(None),
+        };
+        let token = match var("AWS_CONTAINER_AUTHORIZATION_TOKEN_FILE") {
+            Some(path) => Some(fs::read_to_string(path)?.trim().to_string()),
+            None => var("AWS_CONTAINER_AUTHORIZATION_TOKEN"),
+        };
+
+        let mut request = Client::new().get(&url);
+        if let Some(token) = token {
+            request = request.header("Authorization", token);
+        }
+        let response: CredentialsResponse = request
+            .send()
+            .and_then(|r| r.error_for_status())
+            .and_then(|r| r.json())
+            .with_context(|| format!("コンテナの認証情報を取得できませんでした: {}", url))?;
+        Ok(Some(response.into()))
+    }
+
+    // EC2のインスタンスプロファイルの認証情報を取得する。EC2の外で接続できない場合は `None` を返す
+    // https://docs.aws.amazon.com/AWSEC2/latest/UserGuide/instance-metadata-security-credentials.html
+    fn from_instance_metadata() -> Option<Self> {
+        if env::var("AWS_EC2_METADATA_DISABLED").is_ok_and(|v| v.eq_ignore_ascii_case("true")) {
+            return None;
+        }
+        let endpoint = env::var("AWS_EC2_METADATA_SERVICE_ENDPOINT")
+            .unwrap_or_else(|_| IMDS_ENDPOINT.to_string());
+        let endpoint = endpoint.trim_end_matches('/');
+        let client = Client::builder().timeout(IMDS_TIMEOUT).build().ok()?;
+
+        let token = client
+            .put(format!("{}/latest/api/token", endpoint))
+            .header("x-aws-ec2-metadata-token-ttl-seconds", "21600")
+            .send()
+            .and_then(|r| r.error_for_status())
+            .and_then(|r| r.text())
+            .ok()?;
+        let get = |path: &str| {
+            client
+                .get(format!(
+                    "{}/latest/meta-data/iam/security-credentials/{}",
+                    endpoint, path
+                ))
+                .header("x-aws-ec2-metadata-token", &token)
+                .send()
+                .and_then(|r| r.error_for_status())
+        };
+        let roles = get("").and_then(|r| r.text()).ok()?;
+        let role = roles.lines().next()?.trim();
+        let response: CredentialsResponse = get(role).and_then(|r| r.json()).ok()?;
+        Some(response.into())
+    }
+
+    fn from_env() -> Option<Self> {
+        let var = |key: &str| env::var(key).ok().filter(|v| !v.is_empty());
+        Some(Self {
+            access_key_id: var("AWS_ACCESS_KEY_ID")?,
+            secret_access_key: var("AWS_SECRET_ACCESS_KEY")?,
+            session_token: var("AWS_SESSION_TOKEN"),
+            expires_at: None,
+        })
+    }
+
+    /// 有効期限まで `margin` より短いか
+    pub fn expires_within(&self, margin: Duration) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at - Utc::now() < margin)
+    }
+}
+
+/// 使うリージョン
+///
+/// `AWS_REGION`、`AWS_DEFAULT_REGION`、`~/.aws/config` のプロファイルの `region` の順に探す。
+pub fn default_region(profile: Option<&str>) -> Option<String> {
+    env::var("AWS_REGION")
+        .or_else(|_| env::var("AWS_DEFAULT_REGION"))
+        .ok()
+        .filter(|v| !v.is_empty())
+        .or_else(|| {
+            let section = config_section(&profile_name(profile));
+            config_file()
+                .and_then(|path| read_section(&path, &section))
+                .and_then(|mut s| s.remove("region"))
+        })
+}
+
+// 対応していない認証方法が設定されている場合のエラー
+fn unsupported(method: &str, profile: &str) -> anyhow::Error {
+    anyhow!(
+        "{}による認証には対応していません。~/.aws/config に別のプロファイルを作り、credential_process に `aws configure export-credentials --profile {} --format process` を設定して、そのプロファイルを使ってください",
+        method,
+        profile
+    )
+}
+
+fn profile_name(profile: Option<&str>) -> String {
+    profile
+        .map(str::to_string)
+        .or_else(|| env::var("AWS_PROFILE").ok().filter(|v| !v.is_empty()))
+        .unwrap_or_else(|| "default".to_string())
+}
+
+// `~/.aws/config` では、default以外のプロファイルのセクション名に `profile ` が付く
+fn config_section(profile: &str) -> String {
+    if profile == "default" {
+        profile.to_string()
+    } else {
+        format!("profile {}", profile)
+    }
+}
+
+fn credentials_file() -> Option<PathBuf> {
+    env::var_os("AWS_SHARED_CREDENTIALS_FILE")
+        .map(PathBuf::from)
+        .or_else(|| dirs::home_dir().map(|home| home.join(".aws").join("credentials")))
+}
+
+fn config_file() -> Option<PathBuf> {
+    env::var_os("AWS_CONFIG_FILE")
+        .map(PathBuf::from)
+        .or_else(|| dirs::home_dir().map(|home| home.join(".aws").join("config")))
+}
+
+fn read_section(path: &PathBuf, section: &str) -> Option<BTreeMap<String, String>> {
+    let text = fs::read_to_string(path).ok()?;
+    parse_ini(&text).remove(section)
+}
+
+// AWSの設定ファイルのINI形式を読む。入れ子の設定（`s3 =` の下のインデントされた行）は読み飛ばす
+fn parse_ini(text: &str) -> BTreeMap<String, BTreeMap<String, String>> {
+    let mut sections: BTreeMap<String, BTreeMap<String, String>> = BTreeMap::new();
+    let mut current: Option<String> = None;
+    for line in text.lines() {
+        if line.starts_with([' ', '\t']) {
+            continue;
+        }
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(['#', ';']) {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            let name = name.trim().to_string();
+            sections.entry(name.clone()).or_default();
+            current = Some(name);
+        } else if let (Some(section), Some((key, value))) = (&current, line.split_once('=')) {
+            sections
+                .entry(section.clone())
+                .or_default()
+                .insert(key.trim().to_string(), value.trim().to_string());
+        }
+    }
+    sections
+}
+
+/// AWS Signature Version 4でリクエストに署名する
+///
+/// https://docs.aws.amazon.com/IAM/latest/UserGuide/reference_sigv-create-signed-request.html
+pub struct Signer<'a> {
+    pub credentials: &'a Credentials,
+    pub region: &'a str,
+    pub service: &'a str,
+}
+
+impl Signer<'_> {
+    /// 署名したヘッダー（`x-amz-date`、`x-amz-security-token`、`authorization`）を返す
+    ///
+    /// `headers` に渡したヘッダーと `host` も署名の対象になるので、送信するときは同じ値で送る。
+    /// `url` のパスはエンコード済みのものを渡す。
+    pub fn sign(
+        &self,
+        method: &str,
+        url: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+        now: DateTime<Utc>,
+    ) -> Result<Vec<(String, String)>> {
+        let url = reqwest::Url::parse(url)?;
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Err(anyhow!("URLにホストがありません: {}", url)),
+        };
+        let mut signed: BTreeMap<String, String> = headers
+            .iter()
+            .map(|(name, value)| (name.to_ascii_lowercase(), value.trim().to_string()))
+            .collect();
+        signed.insert("host".to_string(), host);
+        signed.insert("x-amz-date".to_string(), amz_date.clone());
+        if let Some(token) = &self.credentials.session_token {
+            signed.insert("x-amz-security-token".to_string(), token.clone());
+        }
+
+        // S3以外のサービスでは、エンコード済みのパスをもう一度エンコードする
+        let canonical_uri = url
+            .path()
+            .split('/')
+            .map(uri_encode)
+            .collect::<Vec<_>>()
+            .join("/");
+        let mut query: Vec<(String, String)> = url
+            .query_pairs()
+            .map(|(k, v)| (uri_encode(&k), uri_encode(&v)))
+            .collect();
+        query.sort();
+        let canonical_query = query
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join("&");
+        let canonical_headers: String = signed
+            .iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value))
+            .collect();
+        let signed_headers = signed.keys().cloned().collect::<Vec<_>>().join(";");
+
+        let canonical_request = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            method,
+            canonical_uri,
+            canonical_query,
+            canonical_headers,
+            signed_headers,
+            hex(&Sha256::digest(body))
+        );
+        let scope = format!("{}/{}/{}/aws4_request", date, self.region, self.service);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let key = format!("AWS4{}", self.credentials.secret_access_key);
+        let key = hmac_sha256(key.as_bytes(), date.as_bytes());
+        let key = hmac_sha256(&key, self.region.as_bytes());
+        let key = hmac_sha256(&key, self.service.as_bytes());
+        let key = hmac_sha256(&key, b"aws4_request");
+        let signature = hex(&hmac_sha256(&key, string_to_sign.as_bytes()));
+
+        let mut result = vec![("x-amz-date".to_string(), amz_date)];
+        if let Some(token) = &self.credentials.session_token {
+            result.push(("x-amz-security-token".to_string(), token.clone()));
+        }
+        result.push((
+            "authorization".to_string(),
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                self.credentials.access_key_id, scope, signed_headers, signature
+            ),
+        ));
+        Ok(result)
+    }
+}
+
+/// URIの1つの要素をエンコードする。英数字と `-_.~` 以外は全て `%XX` にする
+pub fn uri_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // AWSのSigV4のテストスイートの `get-vanilla`
+    #[test]
+    fn sign_get_vanilla() {
+        let credentials = Credentials {
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+            session_token: None,
+            expires_at: None,
+        };
+        let signer = Signer {
+            credentials: &credentials,
+            region: "us-east-1",
+            service: "service",
+        };
+        let now = DateTime::parse_from_rfc3339("2015-08-30T12:36:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        let headers = signer
+            .sign("GET", "https://example.amazonaws.com/", &[], b"", now)
+            .unwrap();
+        assert_eq!(
+            headers[0],
+            ("x-amz-date".to_string(), "20150830T123600Z".to_string())
+        );
+        assert_eq!(
+            headers[1].1,
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, SignedHeaders=host;x-amz-date, Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+        );
+
+        assert_eq!(uri_encode("claude-v1:0"), "claude-v1%3A0");
+        let ini = parse_ini("[default]\nregion = us-west-2\n[profile dev]\ncredential_process = echo {}\ns3 =\n  max_concurrent_requests = 5\n");
+        assert_eq!(ini["default"]["region"], "us-west-2");
+        assert_eq!(ini["profile dev"]["credential_process"], "echo {}");
+        assert!(!ini["profile dev"].contains_key("max_concurrent_requests"));
+
+        // ECSやEC2のインスタンスメタデータサービスは、セッショントークンを `Token` で返す
+        let response: CredentialsResponse = serde_json::from_str(
+            r#"{"Code":"Success","AccessKeyId":"ASIA","SecretAccessKey":"secret","Token":"token","Expiration":"2099-01-01T00:00:00Z"}"#,
+        )
+        .unwrap();
+        let credentials = Credentials::from(response);
+        assert_eq!(credentials.session_token.as_deref(), Some("token"));
+        assert!(!credentials.expires_within(Duration::minutes(5)));
+    }
+}
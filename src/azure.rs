@@ -0,0 +1,129 @@
+use std::{
+    collections::BTreeMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use reqwest::header::{HeaderMap, HeaderValue};
+
+use crate::process::run_command;
+
+/// `api_version` を省略した場合に使うAzure OpenAIのAPIバージョン
+pub const DEFAULT_API_VERSION: &str = "2024-10-21";
+
+// トークンを取得するコマンドの結果を使い回す時間。
+// Entra IDのトークンは1時間ほど有効だが、期限が近いトークンが返ってくることもあるので短めにする
+const TOKEN_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// Azure OpenAIの認証方法
+pub enum AzureAuth {
+    /// `api-key` ヘッダーにAPIキーを入れる
+    ApiKey(String),
+    /// コマンドを実行してEntra IDのアクセストークンを取得し、`Authorization: Bearer` で送る
+    ///
+    /// 例: `az account get-access-token --resource https://cognitiveservices.azure.com --query accessToken -o tsv`
+    TokenCommand(String),
+}
+
+/// Azure OpenAIに接続するための設定
+///
+/// OpenAIのAPIとはURLと認証の方法だけが異なり、bodyとレスポンスの形は同じ。
+pub struct Azure {
+    api_version: String,
+    /// モデル名からデプロイ名への対応。対応がないモデルはモデル名をデプロイ名として使う
+    deployments: BTreeMap<String, String>,
+    auth: AzureAuth,
+    token: Mutex<Option<(String, Instant)>>,
+}
+
+impl Azure {
+    pub fn new(
+        api_version: Option<String>,
+        deployments: BTreeMap<String, String>,
+        auth: AzureAuth,
+    ) -> Self {
+        Self {
+            api_version: api_version.unwrap_or_else(|| DEFAULT_API_VERSION.to_string()),
+            deployments,
+            auth,
+            token: Mutex::new(None),
+        }
+    }
+
+    /// 設定ファイルで対応を指定したモデルの一覧
+    pub fn models(&self) -> Vec<String> {
+        self.deployments.keys().cloned().collect()
+    }
+
+    /// Chat Completions APIのURL
+    ///
+    /// 例: `https://example.openai.azure.com/openai/deployments/gpt-4o/chat/completions?api-version=2024-10-21`
+    pub fn chat_url(&self, base_url: &str, model: &str) -> String {
+        let deployment = self
+            .deployments
+            .get(model)
+            .map(String::as_str)
+            .unwrap_or(model);
+        format!(
+            "{}/openai/deployments/{}/chat/completions?api-version={}",
+            base_url, deployment, self.api_version
+        )
+    }
+
+    /// 認証のヘッダーを追加する
+    pub fn insert_auth_headers(&self, headers: &mut HeaderMap) -> Result<()> {
+        match &self.auth {
+            AzureAuth::ApiKey(key) => {
+                headers.insert("api-key", HeaderValue::from_str(key)?);
+            }
+            AzureAuth::TokenCommand(command) => {
+                let token = self.access_token(command)?;
+                headers.insert(
+                    "Authorization",
+                    HeaderValue::from_str(&format!("Bearer {}", token))?,
+                );
+            }
+        }
+        Ok(())
+    }
+
+    // 取得済みのトークンが新しければそれを使い、古ければコマンドを実行し直す
+    fn access_token(&self, command: &str) -> Result<String> {
+        let mut cached = self.token.lock().unwrap();
+        if let Some((token, fetched_at)) = cached.as_ref() {
+            if fetched_at.elapsed() < TOKEN_TTL {
+                return Ok(token.clone());
+            }
+        }
+        let token = run_command(command)
+            .with_context(|| format!("トークンを取得できませんでした: {}", command))?;
+        *cached = Some((token.clone(), Instant::now()));
+        Ok(token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deployment_url_and_token_command() {
+        let azure = Azure::new(
+            None,
+            BTreeMap::from([("gpt-4o".to_string(), "prod-gpt4o".to_string())]),
+            AzureAuth::TokenCommand("echo token-123".to_string()),
+        );
+        assert_eq!(
+            azure.chat_url("https://example.openai.azure.com", "gpt-4o"),
+            "https://example.openai.azure.com/openai/deployments/prod-gpt4o/chat/completions?api-version=2024-10-21"
+        );
+        assert!(azure
+            .chat_url("https://example.openai.azure.com", "gpt-4o-mini")
+            .contains("/deployments/gpt-4o-mini/"));
+
+        let mut headers = HeaderMap::new();
+        azure.insert_auth_headers(&mut headers).unwrap();
+        assert_eq!(headers["Authorization"], "Bearer token-123");
+    }
+}
@@ -0,0 +1,581 @@
+use std::{collections::BTreeMap, io::Read, sync::Mutex};
+
+use anyhow::{anyhow, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{Duration, Utc};
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue},
+    StatusCode,
+};
+use serde::Deserialize;
+
+use crate::{
+    api_error::ApiError,
+    aws::{uri_encode, Credentials, Signer},
+    cancel,
+    claude_api_res::ClaudeEvent,
+    http,
+};
+
+/// Bedrockで署名に使うサービス名
+const SERVICE: &str = "bedrock";
+
+/// Bedrockが受け付けるAnthropicのAPIのバージョン
+const ANTHROPIC_VERSION: &str = "bedrock-2023-05-31";
+
+// 一時的な認証情報は、有効期限のこの時間前に取得し直す
+const REFRESH_MARGIN_MINUTES: i64 = 5;
+
+/// AWS BedrockでClaudeを使うための設定
+///
+/// bodyとイベントの形はAnthropicのAPIと同じで、URLと認証、ストリームの枠組みが異なる。
+/// モデル名はBedrockのモデルID（`anthropic.claude-3-5-sonnet-20240620-v1:0` や
+/// 推論プロファイルの `us.anthropic.claude-3-5-sonnet-20240620-v1:0`）を使う。
+pub struct Bedrock {
+    region: String,
+    /// bedrock-runtimeのエンドポイント
+    endpoint: String,
+    /// モデル一覧を取得するbedrock（コントロールプレーン）のエンドポイント
+    control_endpoint: String,
+    profile: Option<String>,
+    credentials: Mutex<Option<Credentials>>,
+}
+
+impl Bedrock {
+    pub fn new(region: &str, profile: Option<String>) -> Self {
+        Self {
+            region: region.to_string(),
+            endpoint: format!("https://bedrock-runtime.{}.amazonaws.com", region),
+            control_endpoint: format!("https://bedrock.{}.amazonaws.com", region),
+            profile,
+            credentials: Mutex::new(None),
+        }
+    }
+
+    /// bedrock-runtimeのエンドポイントを変更する（VPCエンドポイントや、手元のスタブサーバーなど）
+    pub fn with_endpoint(mut self, endpoint: &str) -> Self {
+        self.endpoint = endpoint.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// bedrock（コントロールプレーン）のエンドポイントを変更する
+    pub fn with_control_endpoint(mut self, endpoint: &str) -> Self {
+        self.control_endpoint = endpoint.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// 認証情報を探さずに、指定したものを使う
+    pub fn with_credentials(self, credentials: Credentials) -> Self {
+        *self.credentials.lock().unwrap() = Some(credentials);
+        self
+    }
+
+    /// `InvokeModelWithResponseStream` のURL
+    ///
+    /// モデルIDの `:` はエンコードする。
+    pub fn invoke_url(&self, model_id: &str) -> String {
+        format!(
+            "{}/model/{}/invoke-with-response-stream",
+            self.endpoint,
+            uri_encode(model_id)
+        )
+    }
+
+    /// `ListFoundationModels` のURL。コントロールプレーンなので、エンドポイントはbedrock-runtimeと異なる
+    pub fn list_models_url(&self) -> String {
+        format!(
+            "{}/foundation-models?byProvider=anthropic&byOutputModality=TEXT",
+            self.control_endpoint
+        )
+    }
+
+    /// Anthropicのbodyを、Bedrockが受け付ける形にする
+    ///
+    /// モデルはURLで指定し、ストリーミングはAPIで決まるので `model` と `stream` は送らない。
+    pub fn request_body(&self, mut body: serde_json::Value) -> serde_json::Value {
+        if let Some(object) = body.as_object_mut() {
+            object.remove("model");
+            object.remove("stream");
+            object.insert("anthropic_version".into(), ANTHROPIC_VERSION.into());
+        }
+        body
+    }
+
+    /// `headers` に署名のヘッダーを加える
+    pub fn signed_headers(
+        &self,
+        method: &str,
+        url: &str,
+        headers: &[(&'static str, &'static str)],
+        body: &[u8],
+    ) -> Result<HeaderMap> {
+        let credentials = self.credentials()?;
+        let signer = Signer {
+            credentials: &credentials,
+            region: &self.region,
+            service: SERVICE,
+        };
+        let mut header_map = HeaderMap::new();
+        for (name, value) in headers {
+            header_map.insert(*name, HeaderValue::from_static(value));
+        }
+        for (name, value) in signer.sign(method, url, headers, body, Utc::now())? {
+            header_map.insert(
+                HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(&value)?,
+            );
+        }
+        Ok(header_map)
+    }
+
+    /// `ListFoundationModels` のレスポンスから、ストリーミングで使えるモデルIDを取り出す
+    ///
+    /// オンデマンドで呼べないモデルは、リージョンに対応する推論プロファイルのIDにする。
+    pub fn model_ids(&self, list: FoundationModels) -> Vec<String> {
+        let geography = match self.region.split('-').next() {
+            Some("us") => Some("us"),
+            Some("eu") => Some("eu"),
+            Some("ap") => Some("apac"),
+            _ => None,
+        };
+        list.model_summaries
+            .into_iter()
+            .filter(|m| m.response_streaming_supported)
+            .filter_map(|m| {
+                let types = &m.inference_types_supported;
+                if types.iter().any(|t| t == "ON_DEMAND") {
+                    Some(m.model_id)
+                } else if types.iter().any(|t| t == "INFERENCE_PROFILE") {
+                    geography.map(|g| format!("{}.{}", g, m.model_id))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    // 取得済みの認証情報が期限切れに近ければ取得し直す
+    fn credentials(&self) -> Result<Credentials> {
+        let mut cached = self.credentials.lock().unwrap();
+        if let Some(credentials) = cached.as_ref() {
+            if !credentials.expires_within(Duration::minutes(REFRESH_MARGIN_MINUTES)) {
+                return Ok(credentials.clone());
+            }
+        }
+        let credentials = Credentials::load(self.profile.as_deref())?;
+        *cached = Some(credentials.clone());
+        Ok(credentials)
+    }
+}
+
+/// `ListFoundationModels` のレスポンス
+///
+/// ```json
+/// {
+///   "modelSummaries": [
+///     {
+///       "modelId": "anthropic.claude-3-5-sonnet-20240620-v1:0",
+///       "inferenceTypesSupported": ["ON_DEMAND"],
+///       "responseStreamingSupported": true
+///     }
+///   ]
+/// }
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FoundationModels {
+    #[serde(default)]
+    pub model_summaries: Vec<FoundationModel>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FoundationModel {
+    pub model_id: String,
+    #[serde(default)]
+    pub inference_types_supported: Vec<String>,
+    #[serde(default)]
+    pub response_streaming_supported: bool,
+}
+
+/// `application/vnd.amazon.eventstream` の1つのメッセージ
+///
+/// ```text
+/// | 全体の長さ (u32) | ヘッダーの長さ (u32) | 前の8バイトのCRC32 (u32) |
+/// | ヘッダー | ペイロード | ここまで全体のCRC32 (u32) |
+/// ```
+///
+/// https://docs.aws.amazon.com/transcribe/latest/dg/streaming-setting-up.html#streaming-event-stream
+#[derive(Debug, Default)]
+pub struct Frame {
+    /// 値が文字列のヘッダーだけを持つ（`:message-type`、`:event-type` など）
+    pub headers: BTreeMap<String, String>,
+    pub payload: Vec<u8>,
+}
+
+// 1つのメッセージの上限。これより長い値が読めた場合は、壊れたストリームとみなす
+const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/// ストリームから1つのメッセージを読む。メッセージの区切りでストリームが終わった場合は `None` を返す
+pub fn read_frame(reader: &mut impl Read) -> Result<Option<Frame>> {
+    let mut prelude = [0u8; 12];
+    let mut filled = 0;
+    while filled < prelude.len() {
+        match reader.read(&mut prelude[filled..])? {
+            0 if filled == 0 => return Ok(None),
+            0 => return Err(anyhow!("event streamのメッセージが途中で切れています")),
+            n => filled += n,
+        }
+    }
+    let total_len = u32::from_be_bytes(prelude[0..4].try_into()?) as usize;
+    let headers_len = u32::from_be_bytes(prelude[4..8].try_into()?) as usize;
+    let prelude_crc = u32::from_be_bytes(prelude[8..12].try_into()?);
+    if crc32fast::hash(&prelude[..8]) != prelude_crc {
+        return Err(anyhow!("event streamのCRCが一致しません"));
+    }
+    if total_len > MAX_FRAME_LEN || total_len < headers_len + 16 {
+        return Err(anyhow!("event streamのメッセージの長さが不正です"));
+    }
+
+    let mut rest = vec![0u8; total_len - prelude.len()];
+    reader
+        .read_exact(&mut rest)
+        .context("event streamのメッセージが途中で切れています")?;
+    let (data, message_crc) = rest.split_at(rest.len() - 4);
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&prelude);
+    hasher.update(data);
+    if hasher.finalize() != u32::from_be_bytes(message_crc.try_into()?) {
+        return Err(anyhow!("event streamのCRCが一致しません"));
+    }
+
+    let (header_bytes, payload) = data.split_at(headers_len);
+    Ok(Some(Frame {
+        headers: parse_headers(header_bytes)?,
+        payload: payload.to_vec(),
+    }))
+}
+
+// ヘッダーは「名前の長さ (u8)、名前、値の型 (u8)、値」の繰り返し
+fn parse_headers(mut bytes: &[u8]) -> Result<BTreeMap<String, String>> {
+    let mut headers = BTreeMap::new();
+    let take = |bytes: &mut &[u8], n: usize| -> Result<Vec<u8>> {
+        if bytes.len() < n {
+            return Err(anyhow!("event streamのヘッダーが不正です"));
+        }
+        let (head, tail) = bytes.split_at(n);
+        *bytes = tail;
+        Ok(head.to_vec())
+    };
+
+    while !bytes.is_empty() {
+        let name_len = take(&mut bytes, 1)?[0] as usize;
+        let name = String::from_utf8(take(&mut bytes, name_len)?)?;
+        let value_type = take(&mut bytes, 1)?[0];
+        let value_len = match value_type {
+            // true, false
+            0 | 1 => 0,
+            // byte, short, int, long
+            2 => 1,
+            3 => 2,
+            4 => 4,
+            5 => 8,
+            // byte array, string
+            6 | 7 => {
+                let len = take(&mut bytes, 2)?;
+                u16::from_be_bytes([len[0], len[1]]) as usize
+            }
+            // timestamp, uuid
+            8 => 8,
+            9 => 16,
+            _ => {
+                return Err(anyhow!(
+                    "event streamのヘッダーの型が不正です: {}",
+                    value_type
+                ))
+            }
+        };
+        let value = take(&mut bytes, value_len)?;
+        if value_type == 7 {
+            headers.insert(name, String::from_utf8(value)?);
+        }
+    }
+    Ok(headers)
+}
+
+// `chunk` イベントのペイロード。`bytes` にAnthropicのイベントのJSONがBase64で入っている
+#[derive(Debug, Deserialize)]
+struct Chunk {
+    bytes: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ExceptionPayload {
+    #[serde(default, alias = "Message")]
+    message: String,
+}
+
+/// event streamのメッセージを `ClaudeEvent` に変換する。`chunk` 以外のイベントは `None` を返す
+pub fn decode_frame(frame: &Frame) -> Result<Option<ClaudeEvent>> {
+    let header = |name: &str| frame.headers.get(name).map(String::as_str);
+    match header(":message-type") {
+        Some("event") if header(":event-type") == Some("chunk") => {
+            let chunk: Chunk = serde_json::from_slice(&frame.payload)?;
+            let json = STANDARD.decode(chunk.bytes)?;
+            Ok(Some(serde_json::from_slice(&json)?))
+        }
+        Some("event") => Ok(None),
+        Some("exception") => {
+            let exception = header(":exception-type").unwrap_or("exception");
+            let payload: ExceptionPayload =
+                serde_json::from_slice(&frame.payload).unwrap_or_default();
+            let message = format!("{}: {}", exception, payload.message);
+            // レート制限や過負荷は、終了コードで区別できるようにステータスに対応させる
+            let status = match exception {
+                "throttlingException" => StatusCode::TOO_MANY_REQUESTS,
+                "serviceUnavailableException" => StatusCode::SERVICE_UNAVAILABLE,
+                "validationException" => StatusCode::BAD_REQUEST,
+                "internalServerException" | "modelStreamErrorException" => {
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }
+                _ => return Err(anyhow!("Bedrock: {}", message)),
+            };
+            Err(ApiError::new(status, message).into())
+        }
+        _ => Err(anyhow!(
+            "Bedrock: {}",
+            header(":error-message").unwrap_or("不明なエラー")
+        )),
+    }
+}
+
+/// `InvokeModelWithResponseStream` のレスポンスを読み、`ClaudeEvent` を順に返す
+///
+/// Ctrl+Cで中断が要求されると `Cancelled` を返して読み込みを止める。
+/// `http::CancellableReader` で包んだレスポンスを渡すと、データを待っている間も中断できる。
+pub fn read_events(mut reader: impl Read) -> impl Iterator<Item = Result<ClaudeEvent>> {
+    std::iter::from_fn(move || loop {
+        if let Err(err) = cancel::check() {
+            return Some(Err(err));
+        }
+        match read_frame(&mut reader) {
+            Ok(Some(frame)) => match decode_frame(&frame) {
+                Ok(Some(event)) => return Some(Ok(event)),
+                Ok(None) => continue,
+                Err(err) => return Some(Err(err)),
+            },
+            Ok(None) => return None,
+            Err(err) => return Some(Err(http::cancelled_or(err))),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    use super::*;
+    use crate::{
+        api_error::ApiErrorKind,
+        chat_message::{MessageHistory, Role},
+        claude_client::ClaudeClient,
+        params::GenerationParams,
+        provider::{ChatProvider, StreamEvent},
+    };
+
+    // 手元で動かすBedrockのスタブ。受け取ったリクエストの先頭行、ヘッダー、bodyを順に返す
+    fn serve(responses: Vec<(&'static str, Vec<u8>)>) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for (content_type, body) in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut head = String::new();
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    if let Some(len) = line.to_lowercase().strip_prefix("content-length:") {
+                        content_length = len.trim().parse().unwrap();
+                    }
+                    head.push_str(&line);
+                }
+                let mut request_body = vec![0u8; content_length];
+                reader.read_exact(&mut request_body).unwrap();
+                head.push_str(&String::from_utf8(request_body).unwrap());
+                requests.push(head);
+
+                let mut stream = reader.into_inner();
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\ncontent-type: {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                    content_type,
+                    body.len()
+                )
+                .unwrap();
+                stream.write_all(&body).unwrap();
+            }
+            requests
+        });
+        (url, server)
+    }
+
+    fn encode_frame(headers: &[(&str, &str)], payload: &[u8]) -> Vec<u8> {
+        let mut header_bytes = Vec::new();
+        for (name, value) in headers {
+            header_bytes.push(name.len() as u8);
+            header_bytes.extend_from_slice(name.as_bytes());
+            header_bytes.push(7);
+            header_bytes.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            header_bytes.extend_from_slice(value.as_bytes());
+        }
+        let total_len = (16 + header_bytes.len() + payload.len()) as u32;
+        let mut frame = Vec::new();
+        frame.extend_from_slice(&total_len.to_be_bytes());
+        frame.extend_from_slice(&(header_bytes.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&crc32fast::hash(&frame).to_be_bytes());
+        frame.extend_from_slice(&header_bytes);
+        frame.extend_from_slice(payload);
+        frame.extend_from_slice(&crc32fast::hash(&frame).to_be_bytes());
+        frame
+    }
+
+    fn chunk(event: &str) -> Vec<u8> {
+        let payload = serde_json::json!({"bytes": STANDARD.encode(event)}).to_string();
+        encode_frame(
+            &[
+                (":event-type", "chunk"),
+                (":content-type", "application/json"),
+                (":message-type", "event"),
+            ],
+            payload.as_bytes(),
+        )
+    }
+
+    #[test]
+    fn decode_event_stream() {
+        let mut stream = chunk(
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Hello"}}"#,
+        );
+        stream.extend(chunk(
+            r#"{"type":"message_stop","amazon-bedrock-invocationMetrics":{"inputTokenCount":10,"outputTokenCount":2}}"#,
+        ));
+        let events: Vec<ClaudeEvent> = read_events(stream.as_slice())
+            .collect::<Result<_>>()
+            .unwrap();
+        assert!(
+            matches!(&events[0], ClaudeEvent::ContentBlockDelta { delta, .. } if delta.text == "Hello")
+        );
+        assert!(matches!(events[1], ClaudeEvent::MessageStop));
+
+        let throttled = encode_frame(
+            &[
+                (":exception-type", "throttlingException"),
+                (":message-type", "exception"),
+            ],
+            br#"{"message":"Too many requests"}"#,
+        );
+        let err = read_events(throttled.as_slice())
+            .next()
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(ApiErrorKind::classify(&err), ApiErrorKind::RateLimit);
+
+        let mut broken = chunk(r#"{"type":"ping"}"#);
+        let last = broken.len() - 1;
+        broken[last] ^= 0xff;
+        assert!(read_frame(&mut broken.as_slice()).is_err());
+
+        let bedrock = Bedrock::new("us-east-1", None);
+        assert_eq!(
+            bedrock.invoke_url("us.anthropic.claude-3-5-sonnet-20240620-v1:0"),
+            "https://bedrock-runtime.us-east-1.amazonaws.com/model/us.anthropic.claude-3-5-sonnet-20240620-v1%3A0/invoke-with-response-stream"
+        );
+        let body = bedrock
+            .request_body(serde_json::json!({"model": "x", "stream": true, "max_tokens": 10}));
+        assert_eq!(
+            body,
+            serde_json::json!({"anthropic_version": "bedrock-2023-05-31", "max_tokens": 10})
+        );
+    }
+
+    #[test]
+    fn list_and_stream_from_stub() {
+        let models = serde_json::json!({"modelSummaries": [
+            {"modelId": "anthropic.claude-3-5-sonnet-20240620-v1:0", "inferenceTypesSupported": ["ON_DEMAND"], "responseStreamingSupported": true},
+            {"modelId": "anthropic.claude-3-7-sonnet-20250219-v1:0", "inferenceTypesSupported": ["INFERENCE_PROFILE"], "responseStreamingSupported": true},
+            {"modelId": "anthropic.claude-instant-v1:2:100k", "inferenceTypesSupported": ["PROVISIONED"], "responseStreamingSupported": true}
+        ]});
+        let mut stream = chunk(
+            r#"{"type":"message_start","message":{"id":"msg","type":"message","role":"assistant","content":[],"model":"claude","stop_reason":null,"stop_sequence":null,"usage":{"input_tokens":9,"output_tokens":1}}}"#,
+        );
+        stream.extend(chunk(
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Hello"}}"#,
+        ));
+        stream.extend(chunk(
+            r#"{"type":"message_delta","delta":{"stop_reason":"end_turn","stop_sequence":null},"usage":{"output_tokens":3}}"#,
+        ));
+        stream.extend(chunk(r#"{"type":"message_stop"}"#));
+        let (url, server) = serve(vec![
+            ("application/json", models.to_string().into_bytes()),
+            ("application/vnd.amazon.eventstream", stream),
+        ]);
+
+        let bedrock = Bedrock::new("us-east-1", None)
+            .with_endpoint(&url)
+            .with_control_endpoint(&url)
+            .with_credentials(Credentials {
+                access_key_id: "AKIDEXAMPLE".to_string(),
+                secret_access_key: "secret".to_string(),
+                session_token: Some("session".to_string()),
+                expires_at: None,
+            });
+        let mut client = ClaudeClient::new(String::new())
+            .with_instance("bedrock")
+            .with_bedrock(bedrock);
+
+        let models = client.list_models().unwrap();
+        let names: Vec<&str> = models.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "anthropic.claude-3-5-sonnet-20240620-v1:0",
+                "us.anthropic.claude-3-7-sonnet-20250219-v1:0"
+            ]
+        );
+        assert_eq!(models[1].provider.as_deref(), Some("bedrock"));
+
+        client.set_model(models[1].clone());
+        let mut history = MessageHistory::default();
+        history.push(Role::User, "hi");
+        let mut usage = None;
+        let answer = client
+            .send_messages(&history, &GenerationParams::default(), &mut |event| {
+                if let StreamEvent::Usage(u) = event {
+                    usage = Some(u);
+                }
+            })
+            .unwrap();
+        assert_eq!(answer, "Hello");
+        let usage = usage.unwrap();
+        assert_eq!((usage.input_tokens, usage.output_tokens), (9, 3));
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("GET /foundation-models?byProvider=anthropic"));
+        assert!(requests[1].starts_with(
+            "POST /model/us.anthropic.claude-3-7-sonnet-20250219-v1%3A0/invoke-with-response-stream"
+        ));
+        assert!(requests[1].contains("authorization: AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/"));
+        assert!(requests[1].contains("x-amz-security-token: session"));
+        assert!(requests[1].contains(r#""anthropic_version":"bedrock-2023-05-31""#));
+    }
+}
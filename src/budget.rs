@@ -0,0 +1,255 @@
+use std::fmt;
+
+use anyhow::{anyhow, Result};
+use chrono::{Datelike, Local};
+use serde::Deserialize;
+
+use crate::{
+    chat_message::MessageHistory,
+    model::Model,
+    usage::{Usage, UsageLedger, UsageTotals},
+};
+
+/// 上限に対してこの割合を超えたら警告する（`warn_at` を省略した場合）
+pub const DEFAULT_WARN_AT: f64 = 0.8;
+
+/// 利用量の予算
+///
+/// セッション・1日・1か月ごとに、金額（USD）とトークン数のどちらでも上限を指定できる。
+///
+/// ```toml
+/// [budget]
+/// warn_at = 0.8
+/// session = { usd = 1.0 }
+/// daily = { usd = 5.0, tokens = 2000000 }
+/// monthly = { usd = 100.0 }
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Budget {
+    pub session: Option<Limit>,
+    pub daily: Option<Limit>,
+    pub monthly: Option<Limit>,
+    /// 上限に対する警告の閾値（0.0〜1.0）
+    pub warn_at: Option<f64>,
+}
+
+/// 金額とトークン数の上限。両方指定した場合はどちらかを超えた時点で上限に達したとみなす。
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Limit {
+    pub usd: Option<f64>,
+    pub tokens: Option<u64>,
+}
+
+/// 予算の期間
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Period {
+    Session,
+    Daily,
+    Monthly,
+}
+
+impl fmt::Display for Period {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Period::Session => write!(f, "セッション"),
+            Period::Daily => write!(f, "今日"),
+            Period::Monthly => write!(f, "今月"),
+        }
+    }
+}
+
+/// これまでに使った量
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Spent {
+    pub session: UsageTotals,
+    pub daily: UsageTotals,
+    pub monthly: UsageTotals,
+}
+
+impl Spent {
+    /// 台帳から今日と今月の利用を集計する
+    pub fn load(ledger: &UsageLedger, session: UsageTotals) -> Result<Self> {
+        let now = Local::now();
+        let mut spent = Spent {
+            session,
+            ..Default::default()
+        };
+        for record in ledger.records()? {
+            let t = record.timestamp;
+            if t.year() != now.year() || t.month() != now.month() {
+                continue;
+            }
+            spent.monthly.add(record.usage, record.cost);
+            if t.day() == now.day() {
+                spent.daily.add(record.usage, record.cost);
+            }
+        }
+        Ok(spent)
+    }
+
+    fn get(&self, period: Period) -> &UsageTotals {
+        match period {
+            Period::Session => &self.session,
+            Period::Daily => &self.daily,
+            Period::Monthly => &self.monthly,
+        }
+    }
+}
+
+/// 送信前の予算の確認結果
+#[derive(Debug, Clone, PartialEq)]
+pub enum BudgetCheck {
+    Ok,
+    /// 閾値を超えたが、上限には達していない
+    Warn(Vec<String>),
+    /// 上限に達した
+    Exceeded(String),
+}
+
+impl Budget {
+    pub fn is_empty(&self) -> bool {
+        self.session.is_none() && self.daily.is_none() && self.monthly.is_none()
+    }
+
+    /// `other` で指定された項目だけを上書きする
+    pub fn merge(&mut self, other: Budget) {
+        if other.session.is_some() {
+            self.session = other.session;
+        }
+        if other.daily.is_some() {
+            self.daily = other.daily;
+        }
+        if other.monthly.is_some() {
+            self.monthly = other.monthly;
+        }
+        if other.warn_at.is_some() {
+            self.warn_at = other.warn_at;
+        }
+    }
+
+    fn limit(&self, period: Period) -> Option<&Limit> {
+        match period {
+            Period::Session => self.session.as_ref(),
+            Period::Daily => self.daily.as_ref(),
+            Period::Monthly => self.monthly.as_ref(),
+        }
+    }
+
+    /// これまでの利用に次の送信の見積もりを足して、予算と比べる
+    pub fn check(&self, spent: &Spent, estimate: &UsageTotals) -> BudgetCheck {
+        let warn_at = self.warn_at.unwrap_or(DEFAULT_WARN_AT);
+        let mut warnings = Vec::new();
+
+        for period in [Period::Session, Period::Daily, Period::Monthly] {
+            let Some(limit) = self.limit(period) else {
+                continue;
+            };
+            let used = spent.get(period);
+            let used_tokens = used.usage.input_tokens + used.usage.output_tokens;
+            let estimate_tokens = estimate.usage.input_tokens + estimate.usage.output_tokens;
+
+            if let Some(usd) = limit.usd {
+                let message = format!(
+                    "{}の予算 ${} のうち ${:.4} を使用済みです（今回の送信の見積もり ${:.4}）",
+                    period, usd, used.cost, estimate.cost
+                );
+                if used.cost + estimate.cost >= usd {
+                    return BudgetCheck::Exceeded(message);
+                }
+                if used.cost + estimate.cost >= usd * warn_at {
+                    warnings.push(message);
+                }
+            }
+            if let Some(tokens) = limit.tokens {
+                let message = format!(
+                    "{}の予算 {} tokens のうち {} tokens を使用済みです（今回の送信の見積もり {} tokens）",
+                    period, tokens, used_tokens, estimate_tokens
+                );
+                if used_tokens + estimate_tokens >= tokens {
+                    return BudgetCheck::Exceeded(message);
+                }
+                if (used_tokens + estimate_tokens) as f64 >= tokens as f64 * warn_at {
+                    warnings.push(message);
+                }
+            }
+        }
+
+        if warnings.is_empty() {
+            BudgetCheck::Ok
+        } else {
+            BudgetCheck::Warn(warnings)
+        }
+    }
+
+    /// 予算を確認し、上限に達していれば送信を拒否する
+    ///
+    /// `force` の場合は上限に達していても警告にとどめる。戻り値は表示する警告。
+    pub fn enforce(
+        &self,
+        spent: &Spent,
+        estimate: &UsageTotals,
+        force: bool,
+    ) -> Result<Vec<String>> {
+        match self.check(spent, estimate) {
+            BudgetCheck::Ok => Ok(Vec::new()),
+            BudgetCheck::Warn(warnings) => Ok(warnings),
+            BudgetCheck::Exceeded(message) if force => Ok(vec![message]),
+            BudgetCheck::Exceeded(message) => Err(anyhow!(
+                "予算の上限に達したため送信しません。{}。--force を指定すると上限を超えて送信できます",
+                message
+            )),
+        }
+    }
+}
+
+/// 送信前に、履歴のトークン数から入力の料金を見積もる
+///
+/// 出力のトークン数は事前に分からないため、見積もりには含めない。
+pub fn estimate_request(history: &MessageHistory, model: &Model) -> UsageTotals {
+    let tokenizer = model.capabilities().tokenizer;
+    let usage = Usage {
+        input_tokens: tokenizer.count_history(history) as u64,
+        ..Default::default()
+    };
+    let mut estimate = UsageTotals::default();
+    estimate.add(usage, usage.cost(model));
+    estimate
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_limits_and_warnings() {
+        let budget: Budget = toml::from_str(
+            r#"
+            session = { usd = 1.0 }
+            daily = { tokens = 10000 }
+            "#,
+        )
+        .unwrap();
+
+        let totals = |tokens: u64, cost: f64| UsageTotals {
+            usage: Usage {
+                input_tokens: tokens,
+                ..Default::default()
+            },
+            cost,
+        };
+        let mut spent = Spent::default();
+        let estimate = totals(100, 0.01);
+        assert_eq!(budget.check(&spent, &estimate), BudgetCheck::Ok);
+
+        spent.session = totals(100, 0.85);
+        assert!(matches!(budget.check(&spent, &estimate), BudgetCheck::Warn(w) if w.len() == 1));
+
+        spent.daily = totals(9950, 0.0);
+        assert!(matches!(
+            budget.check(&spent, &estimate),
+            BudgetCheck::Exceeded(m) if m.starts_with("今日")
+        ));
+    }
+}
@@ -0,0 +1,51 @@
+use std::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::Result;
+
+// Ctrl+Cが押されたか
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+/// 回答の生成をCtrl+Cで中断したことを表すエラー
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "回答の生成を中断しました")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Ctrl+Cでプロセスを終了せず、中断の要求として記録するハンドラを登録する
+///
+/// 中断の要求が処理される前にもう一度Ctrl+Cを押した場合は、その場で終了する。
+pub fn install_handler() -> Result<()> {
+    ctrlc::set_handler(|| {
+        if CANCELLED.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+    })?;
+    Ok(())
+}
+
+/// Ctrl+C以外の理由で、受信中の回答の中断を要求する
+pub fn request() {
+    CANCELLED.store(true, Ordering::SeqCst);
+}
+
+/// 中断の要求を取り消す。送信を始める前に呼ぶ。
+pub fn reset() {
+    CANCELLED.store(false, Ordering::SeqCst);
+}
+
+/// 中断が要求されていれば `Cancelled` を返す
+pub fn check() -> Result<()> {
+    if CANCELLED.load(Ordering::SeqCst) {
+        return Err(Cancelled.into());
+    }
+    Ok(())
+}
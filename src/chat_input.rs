@@ -1,31 +1,125 @@
 use anyhow::Result;
-use rustyline::{error::ReadlineError, DefaultEditor};
-
-// 標準入力から複数行の文字列を読み込む関数
-pub fn stdin_to_string() -> Result<String> {
-    let mut rl = DefaultEditor::new()?; // rustylineのインスタンスを作成
-
-    let mut buffer = String::new(); // 読み込んだ文字列を格納するためのバッファを作成
-    loop {
-        let readline = rl.readline(""); // プロンプトを表示せずにユーザーからの入力を待つ
-        match readline {
-            Ok(line) => {
-                buffer.push_str(&line); // 入力された行をバッファに追加
-                buffer.push('\n'); // 改行をバッファに追加
-            }
-            Err(ReadlineError::Interrupted) => {
-                // Ctrl+Cで終了
-                std::process::exit(0);
-            }
-            Err(ReadlineError::Eof) => {
-                // Ctrl+Dで入力を終える
-                break;
-            }
-            Err(err) => {
-                println!("Error: {:?}", err);
-                break;
+use rustyline::{
+    completion::{Completer, Pair},
+    error::ReadlineError,
+    highlight::Highlighter,
+    hint::Hinter,
+    history::DefaultHistory,
+    validate::Validator,
+    Cmd, ConditionalEventHandler, Context, Editor, Event, EventContext, EventHandler, Helper,
+    KeyEvent, Movement, RepeatCount,
+};
+
+use crate::command::COMMANDS;
+
+/// ユーザーからの入力を受け付ける
+///
+/// rustylineのインスタンスを保持し、スラッシュコマンドのTab補完を提供する。
+pub struct ChatInput {
+    rl: Editor<CommandCompleter, DefaultHistory>,
+}
+
+impl ChatInput {
+    pub fn new() -> Result<Self> {
+        let mut rl = Editor::new()?; // rustylineのインスタンスを作成
+        rl.set_helper(Some(CommandCompleter));
+        rl.bind_sequence(
+            KeyEvent::ctrl('C'),
+            EventHandler::Conditional(Box::new(ClearLineOnInterrupt)),
+        );
+        Ok(Self { rl })
+    }
+
+    // 標準入力から複数行の文字列を読み込む
+    //
+    // 1行目が `/` から始まる場合はスラッシュコマンドとして、Enterだけで入力を終える。
+    // 何も入力していない状態でCtrl+Cを押した場合は、終了の合図として `None` を返す。
+    pub fn read(&mut self) -> Result<Option<String>> {
+        let mut buffer = String::new(); // 読み込んだ文字列を格納するためのバッファを作成
+        loop {
+            let readline = self.rl.readline(""); // プロンプトを表示せずにユーザーからの入力を待つ
+            match readline {
+                Ok(line) => {
+                    let is_command = buffer.is_empty() && line.starts_with('/');
+                    buffer.push_str(&line); // 入力された行をバッファに追加
+                    buffer.push('\n'); // 改行をバッファに追加
+                    if is_command {
+                        self.rl.add_history_entry(line)?;
+                        break;
+                    }
+                }
+                Err(ReadlineError::Interrupted) => {
+                    // 入力途中のCtrl+Cは入力の取り消し、空のプロンプトでのCtrl+Cは終了
+                    if buffer.is_empty() {
+                        return Ok(None);
+                    }
+                    println!("（入力を取り消しました）");
+                    buffer.clear();
+                    break;
+                }
+                Err(ReadlineError::Eof) => {
+                    // Ctrl+Dで入力を終える
+                    break;
+                }
+                Err(err) => {
+                    println!("Error: {:?}", err);
+                    break;
+                }
             }
         }
+        Ok(Some(buffer)) // 読み込みに成功した場合は、バッファをOkで返す。
+    }
+}
+
+/// 入力中の行があればCtrl+Cでその行を消し、空の行でだけ中断（Interrupted）にする
+struct ClearLineOnInterrupt;
+
+impl ConditionalEventHandler for ClearLineOnInterrupt {
+    fn handle(&self, _: &Event, _: RepeatCount, _: bool, ctx: &EventContext) -> Option<Cmd> {
+        if ctx.line().is_empty() {
+            None
+        } else {
+            Some(Cmd::Kill(Movement::WholeBuffer))
+        }
     }
-    Ok(buffer) // 読み込みに成功した場合は、バッファをOkで返す。
 }
+
+/// スラッシュコマンド名をTab補完する
+pub struct CommandCompleter;
+
+impl Completer for CommandCompleter {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let prefix = &line[..pos];
+        // 引数を入力中の場合は補完しない
+        if !prefix.starts_with('/') || prefix.contains(char::is_whitespace) {
+            return Ok((pos, Vec::new()));
+        }
+
+        let candidates = COMMANDS
+            .iter()
+            .filter(|(name, _)| name.starts_with(prefix))
+            .map(|(name, _)| Pair {
+                display: name.to_string(),
+                replacement: name.to_string(),
+            })
+            .collect();
+        Ok((0, candidates))
+    }
+}
+
+impl Hinter for CommandCompleter {
+    type Hint = String;
+}
+
+impl Highlighter for CommandCompleter {}
+
+impl Validator for CommandCompleter {}
+
+impl Helper for CommandCompleter {}
@@ -1,6 +1,8 @@
 use std::fmt;
 
-#[derive(Debug, Default)]
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 /// APIに送るメッセージ履歴
 ///
 /// ChatGPTへの初期プロンプトおよびユーザーからの質問、ChatGPTからの回答が、順次格納される。
@@ -14,9 +16,86 @@ impl MessageHistory {
         let m = Message::new(role, content);
         self.messages.push(m);
     }
+
+    /// AIの回答を、回答を生成したモデル名と一緒に追加する
+    pub fn push_assistant(&mut self, content: &str, model: &str) {
+        let mut m = Message::new(Role::Assistant, content);
+        m.model = Some(model.to_owned());
+        self.messages.push(m);
+    }
+
+    /// Ctrl+Cで中断された回答を、途中までの内容で追加する
+    pub fn push_truncated_assistant(&mut self, content: &str, model: &str) {
+        self.push_assistant(content, model);
+        if let Some(m) = self.messages.last_mut() {
+            m.truncated = true;
+        }
+    }
+
+    /// 末尾がユーザーのメッセージであれば取り除いて返す
+    ///
+    /// 送信に失敗した質問を履歴から取り消すために使う。
+    pub fn pop_last_user(&mut self) -> Option<Message> {
+        match self.messages.last() {
+            Some(Message {
+                role: Role::User, ..
+            }) => self.messages.pop(),
+            _ => None,
+        }
+    }
+
+    /// 末尾がAIの回答であれば取り除いて返す
+    pub fn pop_last_assistant(&mut self) -> Option<Message> {
+        match self.messages.last() {
+            Some(Message {
+                role: Role::Assistant,
+                ..
+            }) => self.messages.pop(),
+            _ => None,
+        }
+    }
+
+    /// 直前の質問と回答を取り消す。取り消したメッセージの数を返す。
+    pub fn undo(&mut self) -> usize {
+        let mut removed = 0;
+        if self.pop_last_assistant().is_some() {
+            removed += 1;
+        }
+        if self.pop_last_user().is_some() {
+            removed += 1;
+        }
+        removed
+    }
+
+    /// システムプロンプトを返す
+    pub fn system(&self) -> Option<&str> {
+        self.messages
+            .iter()
+            .find(|m| m.role == Role::System)
+            .map(|m| m.content.as_str())
+    }
+
+    /// システムプロンプトを設定する。既に設定されている場合は置き換える。
+    ///
+    /// システムプロンプトは常に履歴の先頭に置く。
+    pub fn set_system(&mut self, content: &str) {
+        self.messages.retain(|m| m.role != Role::System);
+        self.messages.insert(0, Message::new(Role::System, content));
+    }
+
+    /// システムプロンプト以外の履歴を消去する
+    pub fn clear(&mut self) {
+        self.messages.retain(|m| m.role == Role::System);
+    }
+
+    /// システムプロンプトを除いた会話のメッセージ
+    pub fn conversation(&self) -> impl Iterator<Item = &Message> {
+        self.messages.iter().filter(|m| m.role != Role::System)
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Role {
     User,
     System,
@@ -33,10 +112,16 @@ impl fmt::Display for Role {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
     pub role: Role,
     pub content: String,
+    /// 回答を生成したモデル名。途中でモデルを切り替えた場合に、どのモデルの回答かを区別するために使う。
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// 回答の生成を途中で中断したか
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
 }
 
 impl Message {
@@ -44,6 +129,8 @@ impl Message {
         Self {
             role,
             content: content.to_owned(),
+            model: None,
+            truncated: false,
         }
     }
 }
@@ -1,5 +1,8 @@
+use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
 
+use crate::api_error::ApiError;
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
@@ -21,14 +24,50 @@ pub enum ClaudeEvent {
     },
     MessageDelta {
         delta: MessageDelta,
+        usage: Option<Usage>,
     },
     MessageStop,
+    /// ストリームの途中で発生したエラー（例: `{"type":"error","error":{"type":"overloaded_error",...}}`）
+    Error {
+        error: ErrorDetail,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug)]
-struct Usage {
-    input_tokens: Option<u32>,
-    output_tokens: Option<u32>,
+pub struct ErrorDetail {
+    #[serde(rename = "type")]
+    pub error_type: String,
+    #[serde(default)]
+    pub message: String,
+}
+
+impl ErrorDetail {
+    /// エラーの種類を、HTTPのエラーレスポンスで返される場合と同じステータスの `ApiError` にする
+    ///
+    /// ストリームの途中のエラーでも、再試行やフォールバックで過負荷やレート制限として扱えるようにする。
+    pub fn into_api_error(self) -> ApiError {
+        let status = match self.error_type.as_str() {
+            "invalid_request_error" => 400,
+            "authentication_error" => 401,
+            "permission_error" => 403,
+            "not_found_error" => 404,
+            "request_too_large" => 413,
+            "rate_limit_error" => 429,
+            "overloaded_error" => 529,
+            _ => 500,
+        };
+        let status = StatusCode::from_u16(status).unwrap();
+        ApiError::new(status, format!("{}: {}", self.error_type, self.message))
+    }
+}
+
+// message_startでは入力トークン数が、message_deltaでは出力トークン数が送られてくる
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Usage {
+    pub input_tokens: Option<u64>,
+    pub output_tokens: Option<u64>,
+    pub cache_read_input_tokens: Option<u64>,
+    pub cache_creation_input_tokens: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -41,7 +80,7 @@ pub struct Message {
     model: String,
     stop_reason: Option<String>,
     stop_sequence: Option<String>,
-    usage: Option<Usage>,
+    pub usage: Option<Usage>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -60,7 +99,42 @@ pub struct ContentBlockDelta {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MessageDelta {
-    stop_reason: String,
+    stop_reason: Option<String>,
     stop_sequence: Option<String>,
-    usage: Option<Usage>,
+}
+
+///
+/// Claudeのmodel一覧を取得するAPIのレスポンス
+///
+/// ```json
+/// // sample response
+/// {
+///   "data": [
+///     {
+///       "type": "model",
+///       "id": "claude-3-5-sonnet-20241022",
+///       "display_name": "Claude 3.5 Sonnet (New)",
+///       "created_at": "2024-10-22T00:00:00Z"
+///     }
+///   ],
+///   "has_more": true,
+///   "first_id": "claude-3-5-sonnet-20241022",
+///   "last_id": "claude-3-5-sonnet-20241022"
+/// }
+/// ```
+///
+/// `has_more` が `true` の場合は、`after_id` に `last_id` を指定して続きを取得する。
+#[derive(Deserialize, Debug)]
+pub struct ModelList {
+    pub data: Vec<ModelInfo>,
+    pub has_more: bool,
+    pub first_id: Option<String>,
+    pub last_id: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ModelInfo {
+    pub id: String,
+    pub display_name: Option<String>,
+    pub created_at: Option<String>,
 }
@@ -1,21 +1,38 @@
-use std::io::{stdout, BufRead, BufReader, Write};
-
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 
+use chrono::Duration;
 use reqwest::blocking::Client;
 use serde_json::json;
 
 use crate::{
-    chat_input,
-    chat_message::{self, Role},
-    claude_api_res::ClaudeEvent,
+    bedrock::{self, Bedrock, FoundationModels},
+    chat_message::{self, MessageHistory, Role},
+    claude_api_res::{self, ClaudeEvent, ModelList},
+    http,
     model::Campany,
     model::Model,
+    model_cache::{cache_name, cached_models, DEFAULT_TTL_HOURS},
+    params::GenerationParams,
+    provider::{ChatProvider, StreamEvent},
+    usage::Usage,
 };
 
+/// max_tokensが指定されていない場合に使う値
+pub const DEFAULT_MAX_TOKENS: u32 = 4096;
+
+/// AnthropicのAPIのデフォルトのベースURL
+pub const DEFAULT_BASE_URL: &str = "https://api.anthropic.com/v1";
+
 pub struct ClaudeClient {
     claude_token: String,
-    model: Option<String>,
+    base_url: String,
+    /// 設定ファイルで追加した接続先の名前
+    instance: Option<String>,
+    /// 設定ファイルで指定したモデル一覧。空の場合はAPIから取得する
+    models: Vec<String>,
+    /// AWS Bedrockを経由する場合の設定
+    bedrock: Option<Bedrock>,
+    model: Option<Model>,
     client: Client,
 }
 
@@ -23,67 +40,102 @@ impl ClaudeClient {
     pub fn new(claude_token: String) -> Self {
         Self {
             claude_token,
+            base_url: DEFAULT_BASE_URL.to_string(),
+            instance: None,
+            models: Vec::new(),
+            bedrock: None,
             model: None,
             client: Client::new(),
         }
     }
 
-    pub fn set_model(&mut self, model: String) {
-        self.model = Some(model);
+    /// APIのベースURLを変更する
+    pub fn with_base_url(mut self, base_url: &str) -> Self {
+        self.base_url = base_url.trim_end_matches('/').to_string();
+        self
     }
 
+    /// 設定ファイルで追加した接続先として扱う
+    pub fn with_instance(mut self, name: &str) -> Self {
+        self.instance = Some(name.to_string());
+        self
+    }
+
+    /// モデル一覧をAPIから取得せず、指定したものを使う
+    pub fn with_models(mut self, models: Vec<String>) -> Self {
+        self.models = models;
+        self
+    }
+
+    /// AnthropicのAPIではなく、AWS Bedrockを経由して送信する
+    pub fn with_bedrock(mut self, bedrock: Bedrock) -> Self {
+        self.bedrock = Some(bedrock);
+        self
+    }
+
+    /// 組み込みのモデル一覧
+    ///
+    /// APIからモデル一覧を取得できず、キャッシュもない場合に使う。
     pub fn get_model_list(&self) -> Vec<Model> {
         vec![
-            Model {
-                name: "claude-3-5-sonnet-20240620".to_string(),
-                campany: Campany::Claude,
-            },
-            Model {
-                name: "claude-3-opus-20240229".to_string(),
-                campany: Campany::Claude,
-            },
-            Model {
-                name: "claude-3-sonnet-20240229".to_string(),
-                campany: Campany::Claude,
-            },
-            Model {
-                name: "claude-3-haiku-20240307".to_string(),
-                campany: Campany::Claude,
-            },
+            Model::new("claude-opus-4-5-20251101", Campany::Claude),
+            Model::new("claude-sonnet-4-5-20250929", Campany::Claude),
+            Model::new("claude-haiku-4-5-20251001", Campany::Claude),
+            Model::new("claude-opus-4-1-20250805", Campany::Claude),
         ]
     }
 
-    pub fn run_claude(&self, mut messages: chat_message::MessageHistory) -> Result<()> {
-        // ユーザーからの質問を無限ループで受け付ける
+    // APIからモデル一覧を取得する
+    // https://docs.anthropic.com/en/api/models-list
+    //
+    // 1回のリクエストで全てのモデルが返ってこない場合は、`after_id` を指定して続きを取得する。
+    pub fn fetch_models(&self) -> Result<Vec<Model>> {
+        let mut models = Vec::new();
+        let mut after_id: Option<String> = None;
+
         loop {
-            // ユーザーからの入力を受け付ける
-            println!("👤 質問を入力してください。（入力完了時は改行してCtrl+D）>");
-            let message = chat_input::stdin_to_string()?;
+            let mut url = format!("{}/models?limit=1000", self.base_url);
+            if let Some(after_id) = &after_id {
+                url.push_str(&format!("&after_id={}", after_id));
+            }
+            let headers = self.generate_headers()?;
+            let response = http::get_request(&self.client, &url, headers)?;
+            let page: ModelList = response.json()?;
 
-            // 入力した質問を履歴に追加
-            messages.push(Role::User, &message);
+            models.extend(
+                page.data
+                    .into_iter()
+                    .map(|m| Model::new(&m.id, Campany::Claude)),
+            );
 
-            println!("🤖 Claudeからの回答 >");
+            match (page.has_more, page.last_id) {
+                (true, Some(last_id)) => after_id = Some(last_id),
+                _ => break,
+            }
+        }
 
-            // [TODO] エラー時、exitするのではなく、エラー内容を表示してループを継続したい
-            let assistant_response = self.send_messages(&messages)?;
-            messages.push(Role::Assistant, &assistant_response);
+        Ok(models)
+    }
 
-            // 次の質問との間に空行を入れる
-            println!();
-        }
+    // Bedrockで使えるClaudeのモデルIDを取得する
+    // https://docs.aws.amazon.com/bedrock/latest/APIReference/API_ListFoundationModels.html
+    fn fetch_bedrock_models(&self, bedrock: &Bedrock) -> Result<Vec<Model>> {
+        let url = bedrock.list_models_url();
+        let headers = bedrock.signed_headers("GET", &url, &[], b"")?;
+        let response = http::get_request(&self.client, &url, headers)?;
+        let list: FoundationModels = response.json()?;
+        Ok(bedrock
+            .model_ids(list)
+            .iter()
+            .map(|id| self.to_model(id))
+            .collect())
     }
 
-    // APIへPOSTリクエストを送信する
-    // https://docs.anthropic.com/claude/reference/messages_post
-    fn send_messages(&self, message_history: &chat_message::MessageHistory) -> Result<String> {
-        let url = "https://api.anthropic.com/v1/messages";
-        let headers = self.generate_headers()?;
-        let body = self.generate_body_from_history(message_history);
-        let response = self.send_post_request(url, headers, body)?;
-        let joined_string = self.print_chat_stream(response)?;
-        println!();
-        Ok(joined_string)
+    fn to_model(&self, name: &str) -> Model {
+        match &self.instance {
+            Some(instance) => Model::with_provider(name, Campany::Claude, instance),
+            None => Model::new(name, Campany::Claude),
+        }
     }
 
     fn generate_headers(&self) -> Result<reqwest::header::HeaderMap> {
@@ -105,43 +157,51 @@ impl ClaudeClient {
 
     // APIへ送信するbodyを作成する。
     // メッセージ履歴は全て連結して送る必要がある。
+    //
+    // Claudeはsystemロールのメッセージを受け付けないので、システムプロンプトはトップレベルの`system`に入れる。
+    //
+    // Claudeはmax_tokensが必須なので、指定がない場合は`DEFAULT_MAX_TOKENS`（モデルの上限がそれより小さければ上限）を使う。
     fn generate_body_from_history(
         &self,
         message_history: &chat_message::MessageHistory,
+        params: &GenerationParams,
     ) -> serde_json::Value {
-        let messages = message_history
-            .messages
-            .iter()
-            .map(|m| json!({"role": m.role.to_string(), "content": m.content}))
-            .collect::<Vec<_>>();
+        let messages = claude_messages(message_history);
 
         let model = self.model.as_ref().unwrap();
+        let default_max_tokens = model
+            .capabilities()
+            .max_output_tokens
+            .map_or(DEFAULT_MAX_TOKENS, |limit| limit.min(DEFAULT_MAX_TOKENS));
+        let model = &model.name;
 
-        json!({
+        let mut json = json!({
             "stream": true,
             "model": model,
             "messages": messages,
-        })
-    }
+            "max_tokens": params.max_tokens.unwrap_or(default_max_tokens),
+        });
 
-    fn send_post_request(
-        &self,
-        url: &str,
-        headers: reqwest::header::HeaderMap,
-        body: serde_json::Value,
-    ) -> Result<reqwest::blocking::Response> {
-        let res = self.client.post(url).headers(headers).json(&body).send()?;
+        if let Some(temperature) = params.temperature {
+            json["temperature"] = json!(temperature);
+        }
+        if let Some(top_p) = params.top_p {
+            json["top_p"] = json!(top_p);
+        }
+        if !params.stop.is_empty() {
+            json["stop_sequences"] = json!(params.stop);
+        }
 
-        if res.status().is_success() {
-            Ok(res)
-        } else {
-            Err(anyhow!("failed to send POST request: {}", res.text()?))
+        if let Some(system) = message_history.system() {
+            json["system"] = json!(system);
         }
+
+        json
     }
 
-    // print_chat_stream
+    // read_chat_stream
     //
-    // APIから下記のようなデータが連続して送られてくるので、`choices[0].delta.content`を取得して逐次Printする。
+    // APIから下記のようなデータが連続して送られてくるので、`delta.text`を取得して逐次通知する。
     //
     // 以下のようなデータが送られてくる:
     // ```
@@ -183,66 +243,242 @@ impl ClaudeClient {
     // ```
     //
     // `{"type":"message_stop"}` が送られてきたら読み込みを終了し、ループを抜ける。
-    //
-    // 送られてきた `choices[0].delta.content` は `joined_string`に連結し、最後に返す。
-    fn print_chat_stream(&self, response: reqwest::blocking::Response) -> Result<String> {
-        let mut joined_string = String::new();
-
-        // レスポンスを読み込むためのリーダーを作成する
-        let reader = BufReader::new(response);
-
-        // APIからの回答が横に長い場合は、読みづらいので改行する。
-        // ただ、適切な改行を行うためにはTerminalの幅を取得する必要があるため、
-        // 現状はMAXを仮で設定し、実質的に途中の強制改行が発生しないようにしている。
-        let mut line_length = 0;
-        let max_line_length = usize::MAX;
+    fn read_chat_stream(
+        &self,
+        response: reqwest::blocking::Response,
+        on_event: &mut dyn FnMut(StreamEvent),
+    ) -> Result<String> {
+        let mut stream = ClaudeStream::default();
 
         // レスポンスの各行を処理する
-        for line in reader.lines() {
+        for line in http::stream_lines(response) {
             let line = line?;
 
             // "data: "で始まる各行を処理する
             if let Some(data) = line.strip_prefix("data: ") {
                 let event: ClaudeEvent = serde_json::from_str(data.trim())?;
+                if stream.apply(event, on_event)? {
+                    break;
+                }
+            }
+        }
+
+        Ok(stream.finish(on_event))
+    }
+
+    // read_bedrock_stream
+    //
+    // Bedrockは `application/vnd.amazon.eventstream` のバイナリで、SSEと同じ内容のイベントを送ってくる。
+    fn read_bedrock_stream(
+        &self,
+        response: reqwest::blocking::Response,
+        on_event: &mut dyn FnMut(StreamEvent),
+    ) -> Result<String> {
+        let mut stream = ClaudeStream::default();
+        for event in bedrock::read_events(http::CancellableReader::new(response)) {
+            if stream.apply(event?, on_event)? {
+                break;
+            }
+        }
+        Ok(stream.finish(on_event))
+    }
 
-                // 選択肢の各要素を処理する
-                match event {
-                    ClaudeEvent::ContentBlockDelta { delta, .. } => {
-                        let text = delta.text;
-                        // 逐次Printする
-                        print!("{}", text);
-
-                        // 逐次連結する
-                        joined_string.push_str(&text);
-
-                        // 改行コードが含まれている場合は一度文字数をリセットする
-                        // 文中に改行コードが含まれている場合は少しズレるが、気にしない
-                        if text.contains('\n') {
-                            line_length = 0;
-                        }
-
-                        // 文字数をプラスする。
-                        // UTF-8文字としてカウントしたいので、chars().count()を使う。
-                        // https://doc.rust-lang.org/std/string/struct.String.html#utf-8
-                        line_length += text.chars().count();
-
-                        // 100文字に達した場合は改行する
-                        if line_length >= max_line_length {
-                            println!();
-                            line_length = 0;
-                        }
-
-                        // 逐次Printしたものを即座に表示する
-                        stdout().flush().unwrap();
-                    }
-                    ClaudeEvent::MessageStop => {
-                        break;
-                    }
-                    _ => {}
+    fn send_bedrock(
+        &self,
+        bedrock: &Bedrock,
+        body: serde_json::Value,
+        on_event: &mut dyn FnMut(StreamEvent),
+    ) -> Result<String> {
+        let url = bedrock.invoke_url(&self.model.as_ref().unwrap().name);
+        let body = bedrock.request_body(body);
+        // 署名したbodyと同じバイト列が送られるように、送信時と同じ方法で文字列にする
+        let bytes = serde_json::to_vec(&body)?;
+        let headers = bedrock.signed_headers(
+            "POST",
+            &url,
+            &[
+                ("accept", "application/vnd.amazon.eventstream"),
+                ("content-type", "application/json"),
+            ],
+            &bytes,
+        )?;
+        let response = http::send_post_request(&self.client, &url, headers, body, &mut |r| {
+            on_event(StreamEvent::Retry(r.clone()))
+        })?;
+        self.read_bedrock_stream(response, on_event)
+    }
+}
+
+// ストリームで受け取ったイベントから、回答とトークン数を組み立てる
+//
+// 送られてきた `delta.text` は `joined_string` に連結し、最後に返す。
+#[derive(Default)]
+struct ClaudeStream {
+    joined_string: String,
+    usage: Usage,
+}
+
+impl ClaudeStream {
+    // イベントを反映する。`message_stop` を受け取ったら `true` を返す
+    //
+    // ストリームの途中で `error` イベントを受け取った場合は、その種類に応じた `ApiError` を返す。
+    fn apply(&mut self, event: ClaudeEvent, on_event: &mut dyn FnMut(StreamEvent)) -> Result<bool> {
+        match event {
+            ClaudeEvent::MessageStart { message } => {
+                if let Some(u) = message.usage {
+                    apply_claude_usage(&mut self.usage, &u);
                 }
             }
+            ClaudeEvent::ContentBlockDelta { delta, .. } => {
+                // 逐次連結する
+                self.joined_string.push_str(&delta.text);
+                on_event(StreamEvent::Text(delta.text));
+            }
+            ClaudeEvent::MessageDelta { usage: Some(u), .. } => {
+                apply_claude_usage(&mut self.usage, &u);
+            }
+            ClaudeEvent::MessageStop => return Ok(true),
+            ClaudeEvent::Error { error } => return Err(error.into_api_error().into()),
+            _ => {}
+        }
+        Ok(false)
+    }
+
+    fn finish(self, on_event: &mut dyn FnMut(StreamEvent)) -> String {
+        on_event(StreamEvent::Usage(self.usage));
+        self.joined_string
+    }
+}
+
+// message_startとmessage_deltaで送られてくるトークン数を反映する
+//
+// どちらのイベントの値もその時点までの累計なので、送られてきた項目だけを上書きする。
+// Claudeの`input_tokens`はキャッシュから読み込んだトークンを含まないので、合計に足す。
+fn apply_claude_usage(usage: &mut Usage, u: &claude_api_res::Usage) {
+    if let Some(input_tokens) = u.input_tokens {
+        let cache_read = u.cache_read_input_tokens.unwrap_or(0);
+        usage.input_tokens = input_tokens + cache_read + u.cache_creation_input_tokens.unwrap_or(0);
+        usage.cached_tokens = cache_read;
+    }
+    if let Some(output_tokens) = u.output_tokens {
+        usage.output_tokens = output_tokens;
+    }
+}
+
+// メッセージ履歴をClaudeのAPIが受け付ける形に変換する
+//
+// 他のベンダーのモデルで続けてきた履歴は、Claudeの制約を満たさないことがあるので、以下のように整える。
+// - 先頭はuserでなければならないので、先頭のassistantのメッセージは取り除く
+// - userとassistantは交互でなければならないので、同じロールが連続する場合は1つのメッセージに連結する
+fn claude_messages(message_history: &MessageHistory) -> Vec<serde_json::Value> {
+    let mut merged: Vec<(String, String)> = Vec::new();
+    for m in message_history
+        .conversation()
+        .skip_while(|m| m.role != Role::User)
+    {
+        let role = m.role.to_string();
+        match merged.last_mut() {
+            Some((last_role, content)) if *last_role == role => {
+                content.push_str("\n\n");
+                content.push_str(&m.content);
+            }
+            _ => merged.push((role, m.content.clone())),
+        }
+    }
+
+    merged
+        .into_iter()
+        .map(|(role, content)| json!({"role": role, "content": content}))
+        .collect()
+}
+
+impl ChatProvider for ClaudeClient {
+    fn campany(&self) -> Campany {
+        Campany::Claude
+    }
+
+    fn instance(&self) -> Option<&str> {
+        self.instance.as_deref()
+    }
+
+    // モデル一覧はキャッシュし、取得できない場合は古いキャッシュか組み込みの一覧を使う
+    //
+    // Bedrockの場合は、設定ファイルで指定したモデル一覧か、Bedrockから取得した一覧を使う。
+    fn list_models(&self) -> Result<Vec<Model>> {
+        if !self.models.is_empty() {
+            return Ok(self.models.iter().map(|m| self.to_model(m)).collect());
+        }
+        if let Some(bedrock) = &self.bedrock {
+            return self.fetch_bedrock_models(bedrock);
+        }
+        // プロキシなど、接続先ごとに返すモデルが違うため、キャッシュも接続先ごとに分ける
+        Ok(cached_models(
+            &cache_name("claude", &self.base_url),
+            Duration::hours(DEFAULT_TTL_HOURS),
+            || self.fetch_models(),
+            || self.get_model_list(),
+        ))
+    }
+
+    fn set_model(&mut self, model: Model) {
+        self.model = Some(model);
+    }
+
+    fn model(&self) -> Option<&Model> {
+        self.model.as_ref()
+    }
+
+    // APIへPOSTリクエストを送信する
+    // https://docs.anthropic.com/claude/reference/messages_post
+    fn send_messages(
+        &self,
+        message_history: &MessageHistory,
+        params: &GenerationParams,
+        on_event: &mut dyn FnMut(StreamEvent),
+    ) -> Result<String> {
+        let body = self.generate_body_from_history(message_history, params);
+        if let Some(bedrock) = &self.bedrock {
+            return self.send_bedrock(bedrock, body, on_event);
         }
+        let url = format!("{}/messages", self.base_url);
+        let headers = self.generate_headers()?;
+        let response = http::send_post_request(&self.client, &url, headers, body, &mut |r| {
+            on_event(StreamEvent::Retry(r.clone()))
+        })?;
+        self.read_chat_stream(response, on_event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_error::ApiErrorKind;
+
+    #[test]
+    fn convert_history_from_other_vendor() {
+        let mut history = MessageHistory::default();
+        history.set_system("You are a reviewer.");
+        history.push_assistant("Hello from GPT", "gpt-4o");
+        history.push(Role::User, "first");
+        history.push(Role::User, "second");
+        history.push_assistant("answer", "gpt-4o");
+
+        let messages = claude_messages(&history);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0]["role"], "user");
+        assert_eq!(messages[0]["content"], "first\n\nsecond");
+        assert_eq!(messages[1]["role"], "assistant");
+    }
 
-        Ok(joined_string)
+    #[test]
+    fn stream_error_event_is_api_error() {
+        let mut stream = ClaudeStream::default();
+        let event: ClaudeEvent = serde_json::from_str(
+            r#"{"type":"error","error":{"type":"overloaded_error","message":"Overloaded"}}"#,
+        )
+        .unwrap();
+        let err = stream.apply(event, &mut |_| {}).unwrap_err();
+        assert_eq!(ApiErrorKind::classify(&err), ApiErrorKind::Overloaded);
+        assert!(http::is_retryable(&err));
     }
 }
@@ -0,0 +1,173 @@
+use std::{
+    io::{IsTerminal, Read},
+    path::PathBuf,
+};
+
+use anyhow::{anyhow, Result};
+use clap::{Parser, Subcommand};
+
+use crate::params::GenerationParams;
+
+/// ChatGPT/ClaudeのChat APIを呼び出す対話型CLI
+#[derive(Debug, Parser)]
+#[command(version, about, args_conflicts_with_subcommands = true)]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<SubCommand>,
+
+    /// 送信する質問。指定した場合は対話せずに1回だけ回答を出力して終了する
+    #[arg(value_name = "PROMPT")]
+    pub prompt_arg: Option<String>,
+
+    /// 送信する質問。標準入力から渡した内容の前に付けて送信する
+    #[arg(long, value_name = "TEXT")]
+    pub prompt: Option<String>,
+
+    /// 利用するモデル。指定した場合はモデルの選択を省略する
+    #[arg(short, long, value_name = "MODEL")]
+    pub model: Option<String>,
+
+    /// 設定ファイルのプロファイルを使う
+    #[arg(long, value_name = "NAME")]
+    pub profile: Option<String>,
+
+    /// 保存済みのセッション一覧から選んで会話を再開する
+    #[arg(long)]
+    pub resume: bool,
+
+    /// 指定した名前のセッションで会話する（存在すれば再開する）
+    #[arg(long, value_name = "NAME")]
+    pub session: Option<String>,
+
+    /// システムプロンプトを設定する
+    #[arg(long, value_name = "TEXT")]
+    pub system: Option<String>,
+
+    /// 生成のランダム性（OpenAIとGeminiは0〜2、Claudeは0〜1）
+    #[arg(long)]
+    pub temperature: Option<f32>,
+
+    /// 回答の最大トークン数
+    #[arg(long)]
+    pub max_tokens: Option<u32>,
+
+    /// nucleus samplingの確率（0〜1）
+    #[arg(long)]
+    pub top_p: Option<f32>,
+
+    /// 生成を停止する文字列（複数指定可）
+    #[arg(long, value_name = "TEXT")]
+    pub stop: Vec<String>,
+
+    /// 乱数シード（OpenAIのみ）
+    #[arg(long)]
+    pub seed: Option<u64>,
+
+    /// コンテキストウィンドウのトークン数（Ollamaのみ）
+    #[arg(long)]
+    pub num_ctx: Option<u32>,
+
+    /// 回答後にモデルをメモリに残す時間（例: 10m、-1でずっと。Ollamaのみ）
+    #[arg(long, value_name = "DURATION", allow_hyphen_values = true)]
+    pub keep_alive: Option<String>,
+
+    /// 予算の上限に達していても送信する
+    #[arg(long)]
+    pub force: bool,
+}
+
+/// 対話以外の機能を呼び出すサブコマンド
+#[derive(Debug, Subcommand)]
+pub enum SubCommand {
+    /// ファイルまたは標準入力のトークン数を数える
+    Count {
+        /// トークン数の数え方を決めるモデル名か別名
+        #[arg(short, long, default_value = "gpt-4o")]
+        model: String,
+        /// 数えるファイル。省略した場合は標準入力を読む
+        files: Vec<PathBuf>,
+    },
+    /// 月ごとのトークン数と料金の合計を表示する
+    Usage {
+        /// 集計する年月（例: 2024-06）。省略した場合は今月
+        #[arg(long, value_name = "YYYY-MM")]
+        month: Option<String>,
+    },
+}
+
+impl Args {
+    /// 対話せずに1回だけ送信するモードかどうか
+    ///
+    /// 質問が引数で指定された場合か、標準入力がパイプやファイルの場合に該当する。
+    pub fn is_oneshot(&self) -> bool {
+        self.prompt_arg.is_some() || self.prompt.is_some() || !std::io::stdin().is_terminal()
+    }
+
+    /// 1回だけ送信するモードで送る質問を組み立てる
+    ///
+    /// 引数の質問と標準入力の内容の両方がある場合は、空行を挟んで連結する。
+    pub fn oneshot_prompt(&self) -> Result<String> {
+        let mut input = None;
+        if !std::io::stdin().is_terminal() {
+            let mut text = String::new();
+            std::io::stdin().read_to_string(&mut text)?;
+            input = Some(text);
+        }
+        self.join_prompt(input)
+    }
+
+    // 引数の質問と、標準入力から読んだ内容を連結する
+    fn join_prompt(&self, stdin: Option<String>) -> Result<String> {
+        let mut parts: Vec<String> = [&self.prompt_arg, &self.prompt]
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect();
+        parts.extend(stdin.filter(|input| !input.trim().is_empty()));
+
+        if parts.is_empty() {
+            return Err(anyhow!("送信する質問がありません"));
+        }
+        Ok(parts.join("\n\n"))
+    }
+
+    /// コマンドラインで指定された生成パラメータ
+    pub fn generation_params(&self) -> GenerationParams {
+        GenerationParams {
+            temperature: self.temperature,
+            max_tokens: self.max_tokens,
+            top_p: self.top_p,
+            stop: self.stop.clone(),
+            seed: self.seed,
+            num_ctx: self.num_ctx,
+            keep_alive: self.keep_alive.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn join_oneshot_prompt() {
+        let args = Args::parse_from(["aichat-cli", "-m", "gpt-4o", "質問"]);
+        assert_eq!(args.join_prompt(None).unwrap(), "質問");
+
+        let args = Args::parse_from(["aichat-cli", "--prompt", "要約して"]);
+        assert_eq!(
+            args.join_prompt(Some("本文\n".to_string())).unwrap(),
+            "要約して\n\n本文\n"
+        );
+
+        // 空の標準入力は無視する
+        let args = Args::parse_from(["aichat-cli", "質問", "--prompt", "補足"]);
+        assert_eq!(
+            args.join_prompt(Some(" \n".to_string())).unwrap(),
+            "質問\n\n補足"
+        );
+
+        let args = Args::parse_from(["aichat-cli"]);
+        assert!(args.join_prompt(Some(String::new())).is_err());
+    }
+}
@@ -0,0 +1,126 @@
+use anyhow::{anyhow, Result};
+
+/// REPL内で使えるスラッシュコマンド
+///
+/// `/` から始まる入力はAPIへ送信する前にコマンドとして解釈される。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// モデルを選び直す。モデル名か別名を指定した場合は選択画面を省略する
+    Model(Option<String>),
+    /// 現在のモデルをお気に入りに追加、または解除する
+    Favorite,
+    /// システムプロンプトを表示、または設定する
+    System(Option<String>),
+    /// 会話履歴を消去する
+    Clear,
+    /// セッションを保存する。名前を指定した場合はその名前で保存する
+    Save(Option<String>),
+    /// 保存済みのセッションを読み込む
+    Load(Option<String>),
+    /// 直前の回答を生成し直す
+    Retry,
+    /// 直前の質問と回答を取り消す
+    Undo,
+    /// 会話履歴を表示する
+    History,
+    /// 会話履歴のトークン数を表示する
+    Tokens,
+    /// コンテキストウィンドウの使用状況と履歴の削り方を表示する
+    Context,
+    /// 生成パラメータを表示、または設定する
+    Set(Option<String>),
+    /// コマンドの一覧を表示する
+    Help,
+}
+
+/// コマンド名と説明の一覧。`/help` の表示とTab補完に使う。
+pub const COMMANDS: &[(&str, &str)] = &[
+    (
+        "/model",
+        "モデルを選び直す。`/model <name>` でモデル名か別名を指定する",
+    ),
+    ("/favorite", "現在のモデルをお気に入りに追加・解除する"),
+    (
+        "/system",
+        "システムプロンプトを表示する。`/system <text>` で設定する",
+    ),
+    ("/clear", "会話履歴を消去する"),
+    (
+        "/save",
+        "セッションを保存する。`/save <name>` で名前を付けて保存する",
+    ),
+    ("/load", "保存済みのセッションを読み込む"),
+    ("/retry", "直前の回答を生成し直す"),
+    ("/undo", "直前の質問と回答を取り消す"),
+    ("/history", "会話履歴を表示する"),
+    ("/tokens", "会話履歴のトークン数を表示する"),
+    (
+        "/context",
+        "コンテキストウィンドウの使用状況と履歴の削り方を表示する",
+    ),
+    (
+        "/set",
+        "生成パラメータを表示する。`/set <name> <value>` で設定する",
+    ),
+    ("/help", "コマンドの一覧を表示する"),
+];
+
+impl Command {
+    /// 入力がスラッシュコマンドであれば解釈する
+    ///
+    /// `/` から始まらない入力は `None` を返し、通常の質問として扱う。
+    /// 先頭に空白がある場合（インデントしたパスなどを貼り付けた場合）もコマンドとして扱わない。
+    pub fn parse(input: &str) -> Option<Result<Command>> {
+        if !input.starts_with('/') {
+            return None;
+        }
+
+        // 前後の空白を取り除くのは引数だけにする
+        let (name, arg) = match input.split_once(char::is_whitespace) {
+            Some((name, arg)) => (name, Some(arg.trim().to_owned())),
+            None => (input, None),
+        };
+        let arg = arg.filter(|a| !a.is_empty());
+
+        let command = match name {
+            "/model" => Ok(Command::Model(arg)),
+            "/favorite" => Ok(Command::Favorite),
+            "/system" => Ok(Command::System(arg)),
+            "/clear" => Ok(Command::Clear),
+            "/save" => Ok(Command::Save(arg)),
+            "/load" => Ok(Command::Load(arg)),
+            "/retry" => Ok(Command::Retry),
+            "/undo" => Ok(Command::Undo),
+            "/history" => Ok(Command::History),
+            "/tokens" => Ok(Command::Tokens),
+            "/context" => Ok(Command::Context),
+            "/set" => Ok(Command::Set(arg)),
+            "/help" => Ok(Command::Help),
+            _ => Err(anyhow!("unknown command: {} (/helpで一覧を表示)", name)),
+        };
+        Some(command)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_commands() {
+        assert!(Command::parse("hello").is_none());
+        assert!(Command::parse("  /usr/bin/env は何をしますか？\n").is_none());
+        assert_eq!(Command::parse("/clear\n").unwrap().unwrap(), Command::Clear);
+        assert_eq!(
+            Command::parse("/system You are a reviewer.\n")
+                .unwrap()
+                .unwrap(),
+            Command::System(Some("You are a reviewer.".to_string()))
+        );
+        assert_eq!(
+            Command::parse("/save").unwrap().unwrap(),
+            Command::Save(None)
+        );
+        assert!(Command::parse("/unknown").unwrap().is_err());
+    }
+}
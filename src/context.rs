@@ -0,0 +1,304 @@
+use std::fmt;
+
+use serde::Deserialize;
+
+use crate::{
+    chat_message::{Message, MessageHistory, Role},
+    model::Model,
+    params::GenerationParams,
+    tokens::Tokenizer,
+};
+
+/// `keep_last` を省略した場合に残すターン数
+pub const DEFAULT_KEEP_LAST: usize = 10;
+/// 回答のために確保するトークン数（`max_tokens` も `reserve_tokens` も指定しない場合）
+pub const DEFAULT_RESERVE_TOKENS: u32 = 4096;
+/// 要約に使うモデル（`summary_model` を省略した場合）
+pub const DEFAULT_SUMMARY_MODEL: &str = "fast";
+
+/// 履歴がコンテキストウィンドウに収まらない場合の削り方
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrimStrategy {
+    /// 収まるまで古いターンから送信しない
+    #[default]
+    DropOldest,
+    /// システムプロンプトと直近のNターンだけを送信する
+    KeepLast,
+    /// 古いターンを安いモデルで要約し、要約に置き換える
+    Summarize,
+}
+
+impl fmt::Display for TrimStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrimStrategy::DropOldest => write!(f, "drop_oldest（古いターンから省略する）"),
+            TrimStrategy::KeepLast => write!(f, "keep_last（直近のターンだけを送信する）"),
+            TrimStrategy::Summarize => write!(f, "summarize（古いターンを要約する）"),
+        }
+    }
+}
+
+/// コンテキストウィンドウの管理の設定
+///
+/// ```toml
+/// [context]
+/// strategy = "keep_last"
+/// keep_last = 6
+/// summary_model = "gpt-4o-mini"
+/// reserve_tokens = 8000
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ContextConfig {
+    pub strategy: Option<TrimStrategy>,
+    /// `keep_last` で残すターン数
+    pub keep_last: Option<usize>,
+    /// `summarize` で要約に使うモデル名か別名
+    pub summary_model: Option<String>,
+    /// 回答のために確保するトークン数
+    pub reserve_tokens: Option<u32>,
+}
+
+impl ContextConfig {
+    /// `other` で指定された項目だけを上書きする
+    pub fn merge(&mut self, other: ContextConfig) {
+        if other.strategy.is_some() {
+            self.strategy = other.strategy;
+        }
+        if other.keep_last.is_some() {
+            self.keep_last = other.keep_last;
+        }
+        if other.summary_model.is_some() {
+            self.summary_model = other.summary_model;
+        }
+        if other.reserve_tokens.is_some() {
+            self.reserve_tokens = other.reserve_tokens;
+        }
+    }
+
+    pub fn strategy(&self) -> TrimStrategy {
+        self.strategy.unwrap_or_default()
+    }
+
+    pub fn keep_last(&self) -> usize {
+        self.keep_last.unwrap_or(DEFAULT_KEEP_LAST)
+    }
+
+    pub fn summary_model(&self) -> &str {
+        self.summary_model
+            .as_deref()
+            .unwrap_or(DEFAULT_SUMMARY_MODEL)
+    }
+
+    /// 回答のために確保するトークン数
+    ///
+    /// `max_tokens` が指定されていればそれを使い、モデルの出力の上限を超えないようにする。
+    /// Ollamaのようにコンテキストウィンドウが小さい場合でも履歴を送れるように、
+    /// コンテキストウィンドウの1/4までにする。
+    pub fn reserve_tokens(&self, model: &Model, params: &GenerationParams) -> u32 {
+        let reserve = params
+            .max_tokens
+            .or(self.reserve_tokens)
+            .unwrap_or(DEFAULT_RESERVE_TOKENS);
+        let reserve = match model.capabilities().max_output_tokens {
+            Some(max) => reserve.min(max),
+            None => reserve,
+        };
+        reserve.min(context_window(model, params) / 4)
+    }
+
+    /// 履歴に使えるトークン数。コンテキストウィンドウから回答の分を除いたもの。
+    pub fn token_limit(&self, model: &Model, params: &GenerationParams) -> usize {
+        context_window(model, params).saturating_sub(self.reserve_tokens(model, params)) as usize
+    }
+}
+
+// Ollamaで `num_ctx` を指定した場合は、それをコンテキストウィンドウとみなす
+fn context_window(model: &Model, params: &GenerationParams) -> u32 {
+    params
+        .num_ctx
+        .unwrap_or(model.capabilities().context_window)
+}
+
+/// 送信用に削った履歴
+pub struct Trimmed {
+    pub history: MessageHistory,
+    /// 送信しないメッセージの数
+    pub dropped: usize,
+}
+
+/// 会話（システムプロンプト以外）の先頭から何件を送信しないかを決める
+///
+/// 削るのは常にユーザーの質問の直前で、最後の質問は必ず残す。
+/// 最後の質問だけでも上限を超える場合は、最後の質問から送信する。
+pub fn split_point(
+    history: &MessageHistory,
+    tokenizer: Tokenizer,
+    limit: usize,
+    strategy: TrimStrategy,
+    keep_last: usize,
+) -> usize {
+    let conversation: Vec<&Message> = history.conversation().collect();
+    let turn_starts: Vec<usize> = conversation
+        .iter()
+        .enumerate()
+        .filter(|(_, m)| m.role == Role::User)
+        .map(|(i, _)| i)
+        .collect();
+    let Some(&last_turn) = turn_starts.last() else {
+        return 0;
+    };
+
+    let min_split = match strategy {
+        TrimStrategy::KeepLast => turn_starts[turn_starts.len().saturating_sub(keep_last.max(1))],
+        TrimStrategy::DropOldest | TrimStrategy::Summarize => 0,
+    };
+
+    let mut remaining = tokenizer.count_history(history);
+    if min_split == 0 && remaining <= limit {
+        return 0;
+    }
+
+    let mut pos = 0;
+    for &start in &turn_starts {
+        remaining -= conversation[pos..start]
+            .iter()
+            .map(|m| tokenizer.count_message(m))
+            .sum::<usize>();
+        pos = start;
+        if start >= min_split && remaining <= limit {
+            return start;
+        }
+    }
+    last_turn
+}
+
+/// 戦略に従って、コンテキストウィンドウに収まるように履歴を削る
+pub fn trim_history(
+    history: &MessageHistory,
+    tokenizer: Tokenizer,
+    limit: usize,
+    strategy: TrimStrategy,
+    keep_last: usize,
+) -> Trimmed {
+    let split = split_point(history, tokenizer, limit, strategy, keep_last);
+    let mut trimmed = MessageHistory::default();
+    let mut index = 0;
+    for m in &history.messages {
+        if m.role != Role::System {
+            index += 1;
+            if index <= split {
+                continue;
+            }
+        }
+        trimmed.messages.push(m.clone());
+    }
+    Trimmed {
+        history: trimmed,
+        dropped: split,
+    }
+}
+
+/// 会話の先頭から `count` 件を要約させるための履歴
+pub fn summary_request(history: &MessageHistory, count: usize) -> MessageHistory {
+    let transcript: Vec<String> = history
+        .conversation()
+        .take(count)
+        .map(|m| format!("{}: {}", m.role, m.content))
+        .collect();
+
+    let mut request = MessageHistory::default();
+    request.set_system(
+        "以下の会話を、後で会話を続けるのに必要な事実・決定事項・前提を残して簡潔に要約してください。",
+    );
+    request.push(Role::User, &transcript.join("\n\n"));
+    request
+}
+
+/// 会話の先頭から `count` 件を要約に置き換える
+pub fn replace_with_summary(history: &mut MessageHistory, count: usize, summary: &str) {
+    let mut index = 0;
+    history.messages.retain(|m| {
+        if m.role == Role::System {
+            return true;
+        }
+        index += 1;
+        index > count
+    });
+
+    let position = history
+        .messages
+        .iter()
+        .position(|m| m.role != Role::System)
+        .unwrap_or(history.messages.len());
+    let content = format!("（これまでの会話の要約）\n{}", summary);
+    history
+        .messages
+        .insert(position, Message::new(Role::User, &content));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Campany;
+
+    fn history(turns: usize) -> MessageHistory {
+        let mut history = MessageHistory::default();
+        history.set_system("system");
+        for i in 0..turns {
+            history.push(Role::User, &"q".repeat(40));
+            history.push_assistant(&format!("{}{}", i, "a".repeat(39)), "gpt-4o");
+        }
+        history.push(Role::User, "last question");
+        history
+    }
+
+    #[test]
+    fn trim_by_strategy() {
+        let tokenizer = Tokenizer::O200kBase;
+        let history = history(5);
+        let total = tokenizer.count_history(&history);
+        let turn = tokenizer.count_message(&history.messages[1])
+            + tokenizer.count_message(&history.messages[2]);
+
+        let trim = |limit, strategy, keep_last| {
+            trim_history(&history, tokenizer, limit, strategy, keep_last)
+        };
+
+        assert_eq!(trim(total, TrimStrategy::DropOldest, 0).dropped, 0);
+
+        // 1ターン分を超えた分だけ削ると、2ターン（4件）が省略される
+        let trimmed = trim(total - turn - 1, TrimStrategy::DropOldest, 0);
+        assert_eq!(trimmed.dropped, 4);
+        assert_eq!(trimmed.history.system(), Some("system"));
+        assert_eq!(trimmed.history.messages[1].role, Role::User);
+
+        let trimmed = trim(total, TrimStrategy::KeepLast, 2);
+        assert_eq!(trimmed.history.conversation().count(), 3);
+
+        let trimmed = trim(0, TrimStrategy::DropOldest, 0);
+        assert_eq!(trimmed.history.conversation().count(), 1);
+
+        let mut summarized = history.clone();
+        replace_with_summary(&mut summarized, 4, "summary");
+        assert_eq!(summarized.conversation().count(), 8);
+        assert!(summarized.messages[1].content.ends_with("summary"));
+    }
+
+    #[test]
+    fn ollama_keeps_earlier_turns() {
+        let model = Model::new("llama3.2", Campany::Ollama);
+        let params = GenerationParams::default();
+        let config = ContextConfig::default();
+
+        let limit = config.token_limit(&model, &params);
+        assert_eq!(limit, 3_072);
+
+        let history = history(5);
+        let tokenizer = model.capabilities().tokenizer;
+        let trimmed = trim_history(&history, tokenizer, limit, config.strategy(), 0);
+        assert_eq!(trimmed.dropped, 0);
+        assert_eq!(trimmed.history.conversation().count(), 11);
+    }
+}
@@ -0,0 +1,120 @@
+use serde::Deserialize;
+
+use crate::usage::Usage;
+
+// モデル一覧は、下記のようなデータが返ってくる
+//
+// ```json
+// {
+//   "models": [
+//     {
+//       "name": "models/gemini-2.5-flash",
+//       "displayName": "Gemini 2.5 Flash",
+//       "inputTokenLimit": 1048576,
+//       "outputTokenLimit": 65536,
+//       "supportedGenerationMethods": ["generateContent", "countTokens"]
+//     }
+//   ],
+//   "nextPageToken": "..."
+// }
+// ```
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ModelList {
+    #[serde(default)]
+    pub models: Vec<Model>,
+    pub next_page_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Model {
+    /// `models/` から始まる名前
+    pub name: String,
+    #[serde(default)]
+    pub supported_generation_methods: Vec<String>,
+}
+
+impl Model {
+    /// チャットに使えるモデルか
+    pub fn is_chat_model(&self) -> bool {
+        self.supported_generation_methods
+            .iter()
+            .any(|m| m == "generateContent")
+            && !self.name.contains("embedding")
+    }
+}
+
+// `streamGenerateContent?alt=sse` では、下記のようなデータが送られてくる
+//
+// ```
+// data: {"candidates": [{"content": {"parts": [{"text": "こんに"}],"role": "model"},"index": 0}],"usageMetadata": {"promptTokenCount": 8,"totalTokenCount": 8},"modelVersion": "gemini-2.5-flash"}
+//
+// data: {"candidates": [{"content": {"parts": [{"text": "ちは"}],"role": "model"},"finishReason": "STOP","index": 0}],"usageMetadata": {"promptTokenCount": 8,"candidatesTokenCount": 4,"totalTokenCount": 12},"modelVersion": "gemini-2.5-flash"}
+// ```
+//
+// 終わりを表すイベントはなく、最後のデータを送ると接続が閉じられる。
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GenerateContentResponse {
+    #[serde(default)]
+    pub candidates: Vec<Candidate>,
+    pub usage_metadata: Option<UsageMetadata>,
+    pub prompt_feedback: Option<PromptFeedback>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Candidate {
+    pub content: Option<Content>,
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Content {
+    #[serde(default)]
+    pub parts: Vec<Part>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Part {
+    pub text: Option<String>,
+    /// 思考の要約の場合は `true`。回答には含めない
+    #[serde(default)]
+    pub thought: bool,
+}
+
+/// 安全性のフィルタなどで質問が拒否された場合に返ってくる
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PromptFeedback {
+    pub block_reason: Option<String>,
+}
+
+// トークン数は各データに含まれ、その時点までの累計になっている
+//
+// `promptTokenCount` はキャッシュから読み込んだトークン（`cachedContentTokenCount`）を含む。
+// 思考に使ったトークン（`thoughtsTokenCount`）は出力として課金される。
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageMetadata {
+    #[serde(default)]
+    pub prompt_token_count: u64,
+    #[serde(default)]
+    pub candidates_token_count: u64,
+    #[serde(default)]
+    pub thoughts_token_count: u64,
+    #[serde(default)]
+    pub cached_content_token_count: u64,
+}
+
+impl From<&UsageMetadata> for Usage {
+    fn from(usage: &UsageMetadata) -> Self {
+        Usage {
+            input_tokens: usage.prompt_token_count,
+            output_tokens: usage.candidates_token_count + usage.thoughts_token_count,
+            cached_tokens: usage.cached_content_token_count,
+        }
+    }
+}
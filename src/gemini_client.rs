@@ -0,0 +1,303 @@
+use anyhow::{anyhow, Result};
+use chrono::Duration;
+use reqwest::blocking::Client;
+use serde_json::json;
+
+use crate::{
+    chat_message::{MessageHistory, Role},
+    gemini_api_res::{GenerateContentResponse, ModelList},
+    http,
+    model::{Campany, Model},
+    model_cache::{cache_name, cached_models, DEFAULT_TTL_HOURS},
+    params::GenerationParams,
+    provider::{ChatProvider, StreamEvent},
+    usage::Usage,
+};
+
+/// Gemini APIのデフォルトのベースURL
+pub const DEFAULT_BASE_URL: &str = "https://generativelanguage.googleapis.com/v1beta";
+
+pub struct GeminiClient {
+    gemini_token: String,
+    base_url: String,
+    model: Option<Model>,
+    client: Client,
+}
+
+impl GeminiClient {
+    pub fn new(gemini_token: String) -> Self {
+        Self {
+            gemini_token,
+            base_url: DEFAULT_BASE_URL.to_string(),
+            model: None,
+            client: Client::new(),
+        }
+    }
+
+    /// APIのベースURLを変更する
+    pub fn with_base_url(mut self, base_url: &str) -> Self {
+        self.base_url = base_url.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// 組み込みのモデル一覧
+    ///
+    /// APIからモデル一覧を取得できず、キャッシュもない場合に使う。
+    pub fn get_model_list(&self) -> Vec<Model> {
+        vec![
+            Model::new("gemini-2.5-pro", Campany::Gemini),
+            Model::new("gemini-2.5-flash", Campany::Gemini),
+            Model::new("gemini-2.5-flash-lite", Campany::Gemini),
+            Model::new("gemini-2.0-flash", Campany::Gemini),
+        ]
+    }
+
+    // APIからモデル一覧を取得する
+    // https://ai.google.dev/api/models#method:-models.list
+    //
+    // 1回のリクエストで全てのモデルが返ってこない場合は、`pageToken` を指定して続きを取得する。
+    pub fn fetch_models(&self) -> Result<Vec<Model>> {
+        let mut models = Vec::new();
+        let mut page_token: Option<String> = None;
+
+        loop {
+            let mut url = format!("{}/models?pageSize=1000", self.base_url);
+            if let Some(page_token) = &page_token {
+                url.push_str(&format!("&pageToken={}", page_token));
+            }
+            let response = http::get_request(&self.client, &url, self.generate_headers()?)?;
+            let page: ModelList = response.json()?;
+
+            models.extend(page.models.iter().filter(|m| m.is_chat_model()).map(|m| {
+                let name = m.name.strip_prefix("models/").unwrap_or(&m.name);
+                Model::new(name, Campany::Gemini)
+            }));
+
+            match page.next_page_token {
+                Some(token) if !token.is_empty() => page_token = Some(token),
+                _ => break,
+            }
+        }
+
+        Ok(models)
+    }
+
+    // APIを呼び出すのに必要なヘッダーを生成する
+    fn generate_headers(&self) -> Result<reqwest::header::HeaderMap> {
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert(
+            "Content-Type",
+            reqwest::header::HeaderValue::from_static("application/json"),
+        );
+        headers.insert(
+            "x-goog-api-key",
+            reqwest::header::HeaderValue::from_str(&self.gemini_token)?,
+        );
+        Ok(headers)
+    }
+
+    // APIへ送信するbodyを作成する。
+    // メッセージ履歴は全て連結して送る必要がある。
+    //
+    // システムプロンプトは `contents` ではなく、トップレベルの `systemInstruction` に入れる。
+    // 生成パラメータは `generationConfig` にまとめて送る。
+    fn generate_body_from_history(
+        &self,
+        message_history: &MessageHistory,
+        params: &GenerationParams,
+    ) -> serde_json::Value {
+        let mut json = json!({
+            "contents": gemini_contents(message_history),
+        });
+
+        if let Some(system) = message_history.system() {
+            json["systemInstruction"] = json!({"parts": [{"text": system}]});
+        }
+
+        let mut config = serde_json::Map::new();
+        if let Some(temperature) = params.temperature {
+            config.insert("temperature".into(), json!(temperature));
+        }
+        if let Some(top_p) = params.top_p {
+            config.insert("topP".into(), json!(top_p));
+        }
+        if let Some(max_tokens) = params.max_tokens {
+            config.insert("maxOutputTokens".into(), json!(max_tokens));
+        }
+        if !params.stop.is_empty() {
+            config.insert("stopSequences".into(), json!(params.stop));
+        }
+        if let Some(seed) = params.seed {
+            config.insert("seed".into(), json!(seed));
+        }
+        if !config.is_empty() {
+            json["generationConfig"] = serde_json::Value::Object(config);
+        }
+
+        json
+    }
+
+    // read_chat_stream
+    //
+    // `data: ` で始まる各行に `GenerateContentResponse` が送られてくるので、
+    // `candidates[0].content.parts[].text` を取得して逐次通知する。
+    //
+    // トークン数は各データに累計が含まれるので、最後に受け取った値を通知する。
+    fn read_chat_stream(
+        &self,
+        response: reqwest::blocking::Response,
+        on_event: &mut dyn FnMut(StreamEvent),
+    ) -> Result<String> {
+        let mut joined_string = String::new();
+        let mut usage = Usage::default();
+
+        for line in http::stream_lines(response) {
+            let line = line?;
+            let Some(data) = line.strip_prefix("data: ") else {
+                continue;
+            };
+            let chunk: GenerateContentResponse = serde_json::from_str(data.trim())?;
+
+            if let Some(reason) = chunk.prompt_feedback.and_then(|f| f.block_reason) {
+                return Err(anyhow!("Geminiが質問への回答を拒否しました（{}）", reason));
+            }
+            if let Some(u) = &chunk.usage_metadata {
+                usage = u.into();
+            }
+
+            let parts = chunk
+                .candidates
+                .into_iter()
+                .take(1)
+                .filter_map(|c| c.content)
+                .flat_map(|c| c.parts);
+            for part in parts {
+                match part.text {
+                    Some(text) if !part.thought => {
+                        joined_string.push_str(&text);
+                        on_event(StreamEvent::Text(text));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        on_event(StreamEvent::Usage(usage));
+        Ok(joined_string)
+    }
+}
+
+// メッセージ履歴をGeminiのAPIが受け付ける形に変換する
+//
+// Geminiではassistantのロールを `model` と呼ぶ。
+// 他のベンダーのモデルで続けてきた履歴にも対応できるように、Claudeと同じく以下のように整える。
+// - 先頭はuserでなければならないので、先頭のassistantのメッセージは取り除く
+// - userとmodelは交互でなければならないので、同じロールが連続する場合は1つのメッセージに連結する
+fn gemini_contents(message_history: &MessageHistory) -> Vec<serde_json::Value> {
+    let mut merged: Vec<(&str, String)> = Vec::new();
+    for m in message_history
+        .conversation()
+        .skip_while(|m| m.role != Role::User)
+    {
+        let role = match m.role {
+            Role::User => "user",
+            _ => "model",
+        };
+        match merged.last_mut() {
+            Some((last_role, text)) if *last_role == role => {
+                text.push_str("\n\n");
+                text.push_str(&m.content);
+            }
+            _ => merged.push((role, m.content.clone())),
+        }
+    }
+
+    merged
+        .into_iter()
+        .map(|(role, text)| json!({"role": role, "parts": [{"text": text}]}))
+        .collect()
+}
+
+impl ChatProvider for GeminiClient {
+    fn campany(&self) -> Campany {
+        Campany::Gemini
+    }
+
+    // モデル一覧はキャッシュし、取得できない場合は古いキャッシュか組み込みの一覧を使う
+    fn list_models(&self) -> Result<Vec<Model>> {
+        Ok(cached_models(
+            &cache_name("gemini", &self.base_url),
+            Duration::hours(DEFAULT_TTL_HOURS),
+            || self.fetch_models(),
+            || self.get_model_list(),
+        ))
+    }
+
+    fn set_model(&mut self, model: Model) {
+        self.model = Some(model);
+    }
+
+    fn model(&self) -> Option<&Model> {
+        self.model.as_ref()
+    }
+
+    // https://ai.google.dev/api/generate-content#method:-models.streamgeneratecontent
+    fn send_messages(
+        &self,
+        message_history: &MessageHistory,
+        params: &GenerationParams,
+        on_event: &mut dyn FnMut(StreamEvent),
+    ) -> Result<String> {
+        let model = self.model.as_ref().unwrap();
+        let url = format!(
+            "{}/models/{}:streamGenerateContent?alt=sse",
+            self.base_url, model.name
+        );
+        let headers = self.generate_headers()?;
+        let body = self.generate_body_from_history(message_history, params);
+        let response = http::send_post_request(&self.client, &url, headers, body, &mut |r| {
+            on_event(StreamEvent::Retry(r.clone()))
+        })?;
+        self.read_chat_stream(response, on_event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contents_and_system_instruction() {
+        let mut client = GeminiClient::new("key".to_string());
+        client.set_model(Model::new("gemini-2.5-flash", Campany::Gemini));
+
+        let mut history = MessageHistory::default();
+        history.set_system("Be brief.");
+        history.push_assistant("前のモデルの回答", "gpt-4o");
+        history.push(Role::User, "hello");
+        history.push_assistant("hi", "gpt-4o");
+        history.push(Role::User, "again");
+        history.push(Role::User, "and again");
+        let mut params = GenerationParams::default();
+        params.set("max_tokens", "256").unwrap();
+
+        let body = client.generate_body_from_history(&history, &params);
+        assert_eq!(body["systemInstruction"]["parts"][0]["text"], "Be brief.");
+        assert_eq!(body["generationConfig"]["maxOutputTokens"], 256);
+        let contents = body["contents"].as_array().unwrap();
+        assert_eq!(contents.len(), 3);
+        assert_eq!(contents[1]["role"], "model");
+        assert_eq!(contents[2]["parts"][0]["text"], "again\n\nand again");
+
+        let chunk: GenerateContentResponse = serde_json::from_str(
+            r#"{"candidates":[{"content":{"parts":[{"text":"ok"}],"role":"model"},"finishReason":"STOP"}],
+                "usageMetadata":{"promptTokenCount":8,"candidatesTokenCount":4,"thoughtsTokenCount":10,"cachedContentTokenCount":2}}"#,
+        )
+        .unwrap();
+        let usage = Usage::from(chunk.usage_metadata.as_ref().unwrap());
+        assert_eq!(
+            (usage.input_tokens, usage.output_tokens, usage.cached_tokens),
+            (8, 14, 2)
+        );
+    }
+}
@@ -0,0 +1,455 @@
+use std::{
+    collections::hash_map::RandomState,
+    fmt,
+    hash::{BuildHasher, Hasher},
+    io::{self, BufRead, BufReader, Read},
+    sync::mpsc::{self, Receiver, RecvTimeoutError},
+    thread,
+    time::Duration,
+};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use reqwest::blocking::{Client, Response};
+use reqwest::header::HeaderMap;
+
+use crate::{
+    api_error::{ApiError, ApiErrorKind},
+    cancel::{self, Cancelled},
+};
+
+/// 再試行の回数と待ち時間
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// 最初の送信に加えて再試行する回数
+    pub max_retries: u32,
+    /// 1回目の再試行までの待ち時間。再試行のたびに倍になる
+    pub base_delay: Duration,
+    /// 待ち時間の上限。サーバーがこれより長く待つよう指定した場合は再試行しない
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// `attempt` 回目の再試行までの待ち時間
+    ///
+    /// 指数的に伸ばした待ち時間の半分を固定で待ち、残りの半分はランダムにずらす。
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exp = self
+            .base_delay
+            .saturating_mul(1 << attempt.saturating_sub(1).min(16));
+        let delay = exp.min(self.max_delay);
+        let half = delay / 2;
+        half + half.mul_f64(random_fraction())
+    }
+}
+
+/// 再試行するときにUIへ通知する内容
+#[derive(Debug, Clone)]
+pub struct RetryNotice {
+    /// 何回目の再試行か（1から数える）
+    pub attempt: u32,
+    pub max_retries: u32,
+    pub delay: Duration,
+    pub kind: ApiErrorKind,
+}
+
+impl fmt::Display for RetryNotice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}のため、{:.1}秒後に再試行します（{}/{}）",
+            self.kind,
+            self.delay.as_secs_f64(),
+            self.attempt,
+            self.max_retries
+        )
+    }
+}
+
+/// GETリクエストを送信する。2xx以外のステータスは `ApiError` として返す。
+///
+/// レート制限やサーバーの過負荷の場合は、何も表示せずに再試行する。
+pub fn get_request(client: &Client, url: &str, headers: HeaderMap) -> Result<Response> {
+    send_with_retry(
+        &RetryPolicy::default(),
+        || check_status(client.get(url).headers(headers.clone()).send()?),
+        &mut |_| {},
+    )
+}
+
+/// JSONのbodyを付けてPOSTリクエストを送信する。2xx以外のステータスは `ApiError` として返す。
+///
+/// レート制限やサーバーの過負荷、接続の失敗の場合は再試行し、そのたびに `on_retry` に通知する。
+/// 再試行するのはレスポンスを読み始める前だけなので、回答が重複することはない。
+pub fn send_post_request(
+    client: &Client,
+    url: &str,
+    headers: HeaderMap,
+    body: serde_json::Value,
+    on_retry: &mut dyn FnMut(&RetryNotice),
+) -> Result<Response> {
+    send_with_retry(
+        &RetryPolicy::default(),
+        || {
+            check_status(
+                client
+                    .post(url)
+                    .headers(headers.clone())
+                    .json(&body)
+                    .send()?,
+            )
+        },
+        on_retry,
+    )
+}
+
+/// 再試行できるエラーの間は、待ち時間をおいて `send` を繰り返す
+pub fn send_with_retry(
+    policy: &RetryPolicy,
+    mut send: impl FnMut() -> Result<Response>,
+    on_retry: &mut dyn FnMut(&RetryNotice),
+) -> Result<Response> {
+    let mut attempt = 0;
+    loop {
+        let err = match send() {
+            Ok(response) => return Ok(response),
+            Err(err) => err,
+        };
+        if attempt >= policy.max_retries || !is_retryable(&err) {
+            return Err(err);
+        }
+        attempt += 1;
+
+        // サーバーが待ち時間を指定していればそれに従う。長すぎる場合は諦める
+        let delay = match err.downcast_ref::<ApiError>().and_then(|e| e.retry_after) {
+            Some(delay) if delay > policy.max_delay => return Err(err),
+            Some(delay) => delay,
+            None => policy.backoff(attempt),
+        };
+        on_retry(&RetryNotice {
+            attempt,
+            max_retries: policy.max_retries,
+            delay,
+            kind: ApiErrorKind::classify(&err),
+        });
+        sleep_cancellable(delay)?;
+    }
+}
+
+/// ストリーミングのレスポンスを1行ずつ読む
+///
+/// Ctrl+Cで中断が要求されると `Cancelled` を返して読み込みを止める。
+/// サーバーが応答しないまま止まっていても、次のデータを待たずに中断する。
+pub fn stream_lines(response: Response) -> impl Iterator<Item = Result<String>> {
+    BufReader::new(CancellableReader::new(response))
+        .lines()
+        .map(|line| {
+            cancel::check()?;
+            line.map_err(cancelled_or)
+        })
+}
+
+/// 読み込みのエラーを返す。中断が要求されていた場合は `Cancelled` にする
+pub fn cancelled_or(err: impl Into<anyhow::Error>) -> anyhow::Error {
+    match cancel::check() {
+        Err(cancelled) => cancelled,
+        Ok(()) => err.into(),
+    }
+}
+
+// 中断が要求されていないかを確かめる間隔
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// 別のスレッドでレスポンスを読み、読み込みを待っている間も中断の要求を確かめるReader
+///
+/// ブロックしている読み込みそのものは止められないので、中断したときは読み込み用のスレッドを置き去りにする。
+/// スレッドはサーバーが接続を閉じるか、タイムアウトしたときに終わる。
+pub struct CancellableReader {
+    chunks: Receiver<io::Result<Vec<u8>>>,
+    buffer: Vec<u8>,
+    position: usize,
+    is_cancelled: fn() -> bool,
+}
+
+impl CancellableReader {
+    pub fn new(inner: impl Read + Send + 'static) -> Self {
+        Self::with_cancel(inner, || cancel::check().is_err())
+    }
+
+    fn with_cancel(mut inner: impl Read + Send + 'static, is_cancelled: fn() -> bool) -> Self {
+        let (sender, chunks) = mpsc::channel();
+        thread::spawn(move || loop {
+            let mut buffer = vec![0u8; 8 * 1024];
+            match inner.read(&mut buffer) {
+                Ok(n) => {
+                    buffer.truncate(n);
+                    // 受け取る側が中断して破棄された場合と、読み終わった場合は終了する
+                    if sender.send(Ok(buffer)).is_err() || n == 0 {
+                        break;
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    let _ = sender.send(Err(e));
+                    break;
+                }
+            }
+        });
+        Self {
+            chunks,
+            buffer: Vec::new(),
+            position: 0,
+            is_cancelled,
+        }
+    }
+}
+
+impl Read for CancellableReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position >= self.buffer.len() {
+            match self.chunks.recv_timeout(CANCEL_POLL_INTERVAL) {
+                Ok(chunk) => {
+                    let chunk = chunk?;
+                    if chunk.is_empty() {
+                        return Ok(0);
+                    }
+                    self.buffer = chunk;
+                    self.position = 0;
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    if (self.is_cancelled)() {
+                        return Err(io::Error::other(Cancelled));
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.buffer.len() - self.position);
+        buf[..n].copy_from_slice(&self.buffer[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+fn check_status(res: Response) -> Result<Response> {
+    if res.status().is_success() {
+        Ok(res)
+    } else {
+        let status = res.status();
+        let retry_after = retry_after(res.headers(), Utc::now());
+        Err(ApiError::new(status, res.text()?)
+            .with_retry_after(retry_after)
+            .into())
+    }
+}
+
+/// 再試行できるエラーか。接続の失敗とタイムアウトは、リクエストが処理されていないので再試行する
+pub fn is_retryable(err: &anyhow::Error) -> bool {
+    if let Some(api_error) = err.downcast_ref::<ApiError>() {
+        return api_error.is_retryable();
+    }
+    if let Some(e) = err.downcast_ref::<reqwest::Error>() {
+        return e.is_connect() || e.is_timeout();
+    }
+    false
+}
+
+// 待ち時間の途中でもCtrl+Cで中断できるように、少しずつ眠る
+fn sleep_cancellable(delay: Duration) -> Result<()> {
+    const STEP: Duration = Duration::from_millis(100);
+    let mut remaining = delay;
+    while !remaining.is_zero() {
+        cancel::check()?;
+        let step = remaining.min(STEP);
+        thread::sleep(step);
+        remaining -= step;
+    }
+    cancel::check()
+}
+
+/// レスポンスのヘッダーから、再試行までの待ち時間を求める
+///
+/// `retry-after-ms`、`retry-after`（秒数またはHTTP日付）の順に使う。
+/// どちらもなければ、使い切ったレート制限（OpenAIの `x-ratelimit-*`、
+/// Anthropicの `anthropic-ratelimit-*`）のうち、最も遅くリセットされるものまで待つ。
+pub fn retry_after(headers: &HeaderMap, now: DateTime<Utc>) -> Option<Duration> {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+
+    if let Some(ms) = header("retry-after-ms").and_then(|v| v.trim().parse::<f64>().ok()) {
+        return Some(Duration::from_secs_f64(ms.max(0.0) / 1000.0));
+    }
+    if let Some(value) = header("retry-after") {
+        if let Ok(secs) = value.trim().parse::<f64>() {
+            return Some(Duration::from_secs_f64(secs.max(0.0)));
+        }
+        if let Ok(date) = DateTime::parse_from_rfc2822(value.trim()) {
+            return Some(until(date.with_timezone(&Utc), now));
+        }
+    }
+
+    const OPENAI_LIMITS: &[(&str, &str)] = &[
+        (
+            "x-ratelimit-remaining-requests",
+            "x-ratelimit-reset-requests",
+        ),
+        ("x-ratelimit-remaining-tokens", "x-ratelimit-reset-tokens"),
+    ];
+    const ANTHROPIC_LIMITS: &[(&str, &str)] = &[
+        (
+            "anthropic-ratelimit-requests-remaining",
+            "anthropic-ratelimit-requests-reset",
+        ),
+        (
+            "anthropic-ratelimit-tokens-remaining",
+            "anthropic-ratelimit-tokens-reset",
+        ),
+        (
+            "anthropic-ratelimit-input-tokens-remaining",
+            "anthropic-ratelimit-input-tokens-reset",
+        ),
+        (
+            "anthropic-ratelimit-output-tokens-remaining",
+            "anthropic-ratelimit-output-tokens-reset",
+        ),
+    ];
+    let exhausted = |remaining: &str| header(remaining).is_some_and(|v| v.trim() == "0");
+
+    let openai = OPENAI_LIMITS
+        .iter()
+        .filter(|(remaining, _)| exhausted(remaining))
+        .filter_map(|(_, reset)| header(reset).and_then(parse_go_duration));
+    // Anthropicはリセットの時刻をRFC 3339で返す
+    let anthropic = ANTHROPIC_LIMITS
+        .iter()
+        .filter(|(remaining, _)| exhausted(remaining))
+        .filter_map(|(_, reset)| header(reset))
+        .filter_map(|v| DateTime::parse_from_rfc3339(v.trim()).ok())
+        .map(|reset| until(reset.with_timezone(&Utc), now));
+    openai.chain(anthropic).max()
+}
+
+fn until(time: DateTime<Utc>, now: DateTime<Utc>) -> Duration {
+    (time - now).to_std().unwrap_or(Duration::ZERO)
+}
+
+// OpenAIのレート制限のヘッダーで使われる `6m0s` や `20ms` の形の時間を読む
+fn parse_go_duration(s: &str) -> Option<Duration> {
+    let mut total = 0.0;
+    let mut rest = s.trim();
+    if rest.is_empty() {
+        return None;
+    }
+    while !rest.is_empty() {
+        let number_len = rest
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(rest.len());
+        let value: f64 = rest[..number_len].parse().ok()?;
+        rest = &rest[number_len..];
+        let unit_len = rest
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or(rest.len());
+        let scale = match &rest[..unit_len] {
+            "h" => 3600.0,
+            "m" => 60.0,
+            "s" => 1.0,
+            "ms" => 0.001,
+            _ => return None,
+        };
+        total += value * scale;
+        rest = &rest[unit_len..];
+    }
+    Some(Duration::from_secs_f64(total))
+}
+
+// 0以上1未満の乱数。待ち時間をずらすためだけに使うので、標準ライブラリのハッシュのシードで足りる
+fn random_fraction() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(Utc::now().timestamp_nanos_opt().unwrap_or_default() as u64);
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    // 何も送ってこないまま止まっているサーバーの代わり
+    struct Stalled;
+
+    impl Read for Stalled {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            thread::sleep(Duration::from_secs(60));
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn cancel_stalled_read() {
+        let mut text = String::new();
+        CancellableReader::with_cancel(&b"data: hello\n"[..], || false)
+            .read_to_string(&mut text)
+            .unwrap();
+        assert_eq!(text, "data: hello\n");
+
+        let started = std::time::Instant::now();
+        let err = CancellableReader::with_cancel(Stalled, || true)
+            .read(&mut [0u8; 16])
+            .unwrap_err();
+        assert!(err.get_ref().is_some_and(|e| e.is::<Cancelled>()));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn retry_delay_from_headers() {
+        let now = DateTime::parse_from_rfc3339("2024-06-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let headers = |pairs: &[(&'static str, &str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.insert(*name, HeaderValue::from_str(value).unwrap());
+            }
+            headers
+        };
+
+        let h = headers(&[("retry-after", "7")]);
+        assert_eq!(retry_after(&h, now), Some(Duration::from_secs(7)));
+
+        let h = headers(&[("retry-after", "Sat, 01 Jun 2024 00:00:30 GMT")]);
+        assert_eq!(retry_after(&h, now), Some(Duration::from_secs(30)));
+
+        let h = headers(&[
+            ("x-ratelimit-remaining-requests", "0"),
+            ("x-ratelimit-reset-requests", "1m30s"),
+            ("x-ratelimit-remaining-tokens", "100"),
+            ("x-ratelimit-reset-tokens", "6m0s"),
+        ]);
+        assert_eq!(retry_after(&h, now), Some(Duration::from_secs(90)));
+
+        let h = headers(&[
+            ("anthropic-ratelimit-tokens-remaining", "0"),
+            ("anthropic-ratelimit-tokens-reset", "2024-06-01T00:00:12Z"),
+        ]);
+        assert_eq!(retry_after(&h, now), Some(Duration::from_secs(12)));
+
+        assert_eq!(retry_after(&HeaderMap::new(), now), None);
+        assert_eq!(parse_go_duration("20ms"), Some(Duration::from_millis(20)));
+
+        let policy = RetryPolicy::default();
+        for attempt in 1..=3 {
+            let full = policy.base_delay * (1 << (attempt - 1));
+            let delay = policy.backoff(attempt);
+            assert!(delay >= full / 2 && delay <= full);
+        }
+    }
+}
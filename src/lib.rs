@@ -1,7 +1,33 @@
+pub mod api_error;
+pub mod aws;
+pub mod azure;
+pub mod bedrock;
+pub mod budget;
+pub mod cancel;
 pub mod chat_input;
 pub mod chat_message;
 pub mod claude_api_res;
 pub mod claude_client;
+pub mod cli;
+pub mod command;
+pub mod config;
+pub mod context;
+pub mod gemini_api_res;
+pub mod gemini_client;
+pub mod http;
 pub mod model;
+pub mod model_cache;
+pub mod ollama_api_res;
+pub mod ollama_client;
+pub mod oneshot;
 pub mod openai_api_res;
 pub mod openai_client;
+pub mod params;
+pub mod picker;
+pub mod preferences;
+pub mod process;
+pub mod provider;
+pub mod repl;
+pub mod session;
+pub mod tokens;
+pub mod usage;
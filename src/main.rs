@@ -1,76 +1,419 @@
 use aichat_cli::{
-    chat_message::{self},
+    api_error::ApiErrorKind,
+    aws,
+    azure::{Azure, AzureAuth},
+    bedrock::Bedrock,
+    chat_message::MessageHistory,
     claude_client,
-    model::{Campany, Model},
-    openai_client,
+    cli::{Args, SubCommand},
+    config::{Config, Profile, ProviderConfig, ProviderKind},
+    gemini_client,
+    model::{self, Campany},
+    ollama_client,
+    oneshot::run_oneshot,
+    openai_client, picker,
+    provider::{model_not_available, ChatProvider},
+    repl::Repl,
+    session::{Session, SessionStore},
+    usage::UsageLedger,
 };
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
+use chrono::{Datelike, Local};
+use clap::Parser;
 use dotenv::dotenv;
-use requestty::Question;
-use std::env;
+use std::{
+    env, fs,
+    io::{self, Read},
+};
 
 fn main() {
     match run() {
         Ok(_) => {}
         Err(e) => {
             eprintln!("error: {}", e);
-            std::process::exit(1);
+            std::process::exit(ApiErrorKind::classify(&e).exit_code());
         }
     };
 }
 
 fn run() -> Result<()> {
-    // 必要な環境変数をここで確認
+    let args = Args::parse();
+
     dotenv().ok();
-    let openai_token =
-        env::var("OPENAI_API_KEY").expect("環境変数にOPENAI_API_KEYをセットしてください");
-    let anthropic_token =
-        env::var("ANTHROPIC_API_KEY").expect("環境変数にANTHROPIC_API_KEYをセットしてください");
 
-    let mut gpt_client = openai_client::ChatGPTClient::new(openai_token);
-    let mut claude_client = claude_client::ClaudeClient::new(anthropic_token);
+    // 設定ファイルから利用するプロファイルを読み込む
+    let config = Config::load()?;
+    model::init_registry(&config.models, &config.aliases, &config.provider_kinds())?;
+
+    // サブコマンドはAPIキーを必要としない
+    if let Some(command) = &args.command {
+        return run_subcommand(command);
+    }
+    let profile = config
+        .profile(args.profile.as_deref())?
+        .cloned()
+        .unwrap_or_default();
+
+    // APIキーがセットされているベンダーだけを有効にする
+    let mut providers = build_providers(&profile, &config);
+    if providers.is_empty() {
+        return Err(anyhow!(
+            "APIキーが見つかりません。環境変数{}のいずれかをセットしてください",
+            Campany::ALL.map(|c| config.api_key_env(c)).join("、")
+        ));
+    }
 
-    // ユーザーにモデルを選択させる
-    let selected_model = select_model_input(&claude_client, &gpt_client)?;
+    // コマンドラインの指定をプロファイルより優先する
+    let system = args.system.clone().or_else(|| profile.system.clone());
+    let mut params = profile.params.clone();
+    params.merge(&args.generation_params());
+    let enabled: Vec<Campany> = providers.iter().map(|p| p.campany()).collect();
+    let cli_model = args
+        .model
+        .as_deref()
+        .map(|name| model::registry().resolve(name, &enabled));
 
-    // 初期メッセージを追加
-    let messages = chat_message::MessageHistory::default();
+    // 質問が引数や標準入力で渡された場合は、対話せずに1回だけ送信する
+    if args.is_oneshot() {
+        let model = cli_model
+            .or_else(|| profile.model(&enabled))
+            .ok_or_else(|| {
+                anyhow!(
+                    "質問を引数で渡す場合は --model またはプロファイルでモデルを指定してください"
+                )
+            })?;
+        if !providers.iter().any(|p| p.serves(&model)) {
+            return Err(model_not_available(&model));
+        }
+        let mut chain = vec![model];
+        chain.extend(profile.fallback_models(&enabled));
 
-    match selected_model.campany {
-        Campany::Claude => {
-            claude_client.set_model(selected_model.name.to_owned());
-            claude_client.run_claude(messages)?;
+        let mut history = MessageHistory::default();
+        if let Some(system) = &system {
+            history.set_system(system);
         }
-        Campany::OpenAI => {
-            gpt_client.set_model(selected_model);
-            gpt_client.run_chatgpt(messages)?;
+        let prompt = args.oneshot_prompt()?;
+        return run_oneshot(
+            &mut providers,
+            &chain,
+            history,
+            &params,
+            &prompt,
+            &config.context,
+            &config.budget,
+            args.force,
+        );
+    }
+
+    // 有効になっていないベンダーがあれば、有効にする方法を案内する
+    for campany in Campany::ALL {
+        if !providers
+            .iter()
+            .any(|p| p.campany() == campany && p.instance().is_none())
+        {
+            eprintln!(
+                "ℹ️ 環境変数{}をセットすると{}のモデルも利用できます",
+                config.api_key_env(campany),
+                campany
+            );
         }
     }
 
-    Ok(())
+    // 新規または再開するセッションを決める
+    let store = SessionStore::open_default()?;
+    let mut session = open_session(&args, &store)?;
+    // コマンドラインの指定は常に反映し、プロファイルのシステムプロンプトは未設定の場合だけ使う
+    if let Some(system) = &args.system {
+        session.history.set_system(system);
+    } else if let (Some(system), None) = (&system, session.history.system()) {
+        session.history.set_system(system);
+    }
+    session.params.merge(&params);
+
+    // --modelの指定、再開したセッションのモデル、プロファイルのモデルの順に使い、
+    // いずれもなければユーザーにモデルを選択させる
+    let selected_model = match cli_model
+        .or_else(|| session.model.clone())
+        .or_else(|| profile.model(&enabled))
+    {
+        Some(model) => model,
+        None => picker::select_model_input(&providers)?,
+    };
+    session.params.validate(&selected_model)?;
+
+    Repl::new(providers, selected_model, session, store)?
+        .with_budget(config.budget, args.force)
+        .with_context(config.context)
+        .with_fallbacks(profile.fallback_models(&enabled))
+        .run()
 }
 
-/// ユーザーにモデルを選択させる
-fn select_model_input(
-    claude_client: &claude_client::ClaudeClient,
-    gpt_client: &openai_client::ChatGPTClient,
-) -> Result<Model, anyhow::Error> {
-    let claude_models = claude_client.get_model_list();
-    let openai_models = gpt_client.fetch_models()?;
-
-    let models: Vec<Model> = claude_models
-        .iter()
-        .chain(openai_models.iter())
-        .cloned()
+fn run_subcommand(command: &SubCommand) -> Result<()> {
+    match command {
+        SubCommand::Count { model, files } => {
+            let model = model::registry().resolve(model, &Campany::ALL);
+            let tokenizer = model.capabilities().tokenizer;
+            if files.is_empty() {
+                let mut text = String::new();
+                io::stdin().read_to_string(&mut text)?;
+                println!("{}", tokenizer.count(&text));
+                return Ok(());
+            }
+
+            let mut total = 0;
+            for path in files {
+                let text = fs::read_to_string(path)
+                    .with_context(|| format!("failed to read {}", path.display()))?;
+                let count = tokenizer.count(&text);
+                total += count;
+                println!("{}\t{}", count, path.display());
+            }
+            if files.len() > 1 {
+                println!("{}\ttotal", total);
+            }
+            Ok(())
+        }
+        SubCommand::Usage { month } => {
+            let (year, month) = match month {
+                Some(month) => parse_month(month)?,
+                None => {
+                    let now = Local::now();
+                    (now.year(), now.month())
+                }
+            };
+            let ledger = UsageLedger::open_default()?;
+            print!("{}", ledger.monthly_report(year, month)?);
+            Ok(())
+        }
+    }
+}
+
+// `2024-06` の形の文字列を年と月に分ける
+fn parse_month(s: &str) -> Result<(i32, u32)> {
+    let (year, month) = s
+        .split_once('-')
+        .ok_or_else(|| anyhow!("年月はYYYY-MMの形で指定してください: {}", s))?;
+    let year: i32 = year.parse()?;
+    let month: u32 = month.parse()?;
+    if !(1..=12).contains(&month) {
+        return Err(anyhow!("月は1から12の範囲で指定してください: {}", s));
+    }
+    Ok((year, month))
+}
+
+/// APIキーがセットされているベンダーと、設定ファイルで追加した接続先のクライアントを作る
+///
+/// ベースURLはプロファイルの `base_url`（プロファイルのベンダーだけ）、設定ファイルの
+/// `[providers.<ベンダー>]`、組み込みの既定値の順に使う。
+fn build_providers(profile: &Profile, config: &Config) -> Vec<Box<dyn ChatProvider>> {
+    // 接続先を作る前なので、別名は環境変数がセットされているベンダーから解決する
+    let with_key: Vec<Campany> = Campany::ALL
+        .into_iter()
+        .filter(|c| env::var(config.api_key_env(*c)).is_ok_and(|v| !v.is_empty()))
         .collect();
-    let select = Question::select("theme")
-        .should_loop(false)
-        .message("🤖 利用するモデルを選択してください (Ctrl+c to exit)")
-        .choices(models.clone())
-        .default(0)
-        .build();
-    let answer = requestty::prompt_one(select)?;
-    let model_index = &answer.as_list_item().unwrap().index;
-    let model = models[*model_index].clone();
-    Ok(model.to_owned())
+    let profile_campany = profile
+        .model(&with_key)
+        .map(|m| m.campany)
+        .or(profile.provider);
+    let base_url = |c: Campany| {
+        profile
+            .base_url
+            .as_deref()
+            .filter(|_| profile_campany == Some(c))
+            .or_else(|| {
+                config
+                    .builtin_provider(c)
+                    .and_then(|p| p.base_url.as_deref())
+            })
+    };
+
+    let mut providers: Vec<Box<dyn ChatProvider>> = Vec::new();
+    for campany in Campany::ALL {
+        let token = env::var(config.api_key_env(campany))
+            .ok()
+            .filter(|t| !t.is_empty());
+        // OllamaはAPIキーが不要なので、設定ファイルに [providers.ollama] があれば環境変数がなくても有効にする
+        let token = match token {
+            Some(token) => token,
+            None if campany == Campany::Ollama && config.builtin_provider(campany).is_some() => {
+                String::new()
+            }
+            None => continue,
+        };
+
+        let provider: Box<dyn ChatProvider> = match campany {
+            Campany::Claude => {
+                let mut client = claude_client::ClaudeClient::new(token);
+                if let Some(url) = base_url(campany) {
+                    client = client.with_base_url(url);
+                }
+                Box::new(client)
+            }
+            Campany::OpenAI => {
+                let mut client = openai_client::ChatGPTClient::new(token).with_stream_usage(
+                    config
+                        .builtin_provider(campany)
+                        .and_then(|p| p.stream_usage),
+                );
+                if let Some(url) = base_url(campany) {
+                    client = client.with_base_url(url);
+                }
+                Box::new(client)
+            }
+            Campany::Gemini => {
+                let mut client = gemini_client::GeminiClient::new(token);
+                if let Some(url) = base_url(campany) {
+                    client = client.with_base_url(url);
+                }
+                Box::new(client)
+            }
+            // Ollamaの場合、環境変数の値はAPIキーではなくホスト
+            Campany::Ollama => {
+                let url = base_url(campany)
+                    .map(str::to_string)
+                    .unwrap_or_else(|| ollama_client::host_url(&token));
+                Box::new(ollama_client::OllamaClient::new(&url))
+            }
+        };
+        providers.push(provider);
+    }
+
+    for (name, provider) in config.custom_providers() {
+        match build_custom_provider(name, provider) {
+            Ok(provider) => providers.push(provider),
+            Err(e) => eprintln!("ℹ️ {}", e),
+        }
+    }
+    providers
+}
+
+/// 設定ファイルで追加したOpenAI互換の接続先のクライアントを作る
+///
+/// `api_key_env` を指定した場合は、その環境変数がセットされているときだけ有効にする。
+/// Azure OpenAIの場合は、`api_key_env` か `token_command` のどちらかで認証する。
+fn build_custom_provider(name: &str, provider: &ProviderConfig) -> Result<Box<dyn ChatProvider>> {
+    if provider.kind == ProviderKind::Bedrock {
+        return build_bedrock_provider(name, provider);
+    }
+    let base_url = provider.base_url.as_deref().ok_or_else(|| {
+        anyhow!(
+            "接続先{}は base_url が指定されていないため利用できません",
+            name
+        )
+    })?;
+    if provider.kind == ProviderKind::Azure {
+        return build_azure_provider(name, base_url, provider);
+    }
+    let token = match &provider.api_key_env {
+        Some(key) => env::var(key)
+            .ok()
+            .filter(|t| !t.is_empty())
+            .ok_or_else(|| {
+                anyhow!(
+                    "環境変数{}をセットすると接続先{}のモデルも利用できます",
+                    key,
+                    name
+                )
+            })?,
+        None => String::new(),
+    };
+    let client = openai_client::ChatGPTClient::new(token)
+        .with_base_url(base_url)
+        .with_instance(name)
+        .with_models(provider.models.clone())
+        .with_stream_usage(provider.stream_usage);
+    Ok(Box::new(client))
+}
+
+/// Azure OpenAIのクライアントを作る
+fn build_azure_provider(
+    name: &str,
+    base_url: &str,
+    provider: &ProviderConfig,
+) -> Result<Box<dyn ChatProvider>> {
+    let auth = match (&provider.token_command, &provider.api_key_env) {
+        (Some(command), _) => AzureAuth::TokenCommand(command.clone()),
+        (None, Some(key)) => AzureAuth::ApiKey(
+            env::var(key)
+                .ok()
+                .filter(|t| !t.is_empty())
+                .ok_or_else(|| {
+                    anyhow!(
+                        "環境変数{}をセットすると接続先{}のモデルも利用できます",
+                        key,
+                        name
+                    )
+                })?,
+        ),
+        (None, None) => {
+            return Err(anyhow!(
+                "接続先{}は api_key_env か token_command を指定してください",
+                name
+            ))
+        }
+    };
+    if provider.deployments.is_empty() && provider.models.is_empty() {
+        return Err(anyhow!(
+            "接続先{}は deployments でモデルとデプロイの対応を指定してください",
+            name
+        ));
+    }
+
+    let azure = Azure::new(
+        provider.api_version.clone(),
+        provider.deployments.clone(),
+        auth,
+    );
+    let client = openai_client::ChatGPTClient::new(String::new())
+        .with_base_url(base_url)
+        .with_instance(name)
+        .with_models(provider.models.clone())
+        .with_azure(azure)
+        .with_stream_usage(provider.stream_usage);
+    Ok(Box::new(client))
+}
+
+/// AWS BedrockのClaudeのクライアントを作る
+///
+/// 認証情報は送信するときに読むので、ここではリージョンが決まるかだけを確かめる。
+/// `base_url` と `control_url` を指定した場合は、bedrock-runtimeとbedrockのエンドポイントとして使う。
+fn build_bedrock_provider(name: &str, provider: &ProviderConfig) -> Result<Box<dyn ChatProvider>> {
+    let region = provider
+        .region
+        .clone()
+        .or_else(|| aws::default_region(provider.profile.as_deref()))
+        .ok_or_else(|| {
+            anyhow!(
+                "接続先{}は region を指定するか、環境変数AWS_REGIONをセットしてください",
+                name
+            )
+        })?;
+    let mut bedrock = Bedrock::new(&region, provider.profile.clone());
+    if let Some(base_url) = &provider.base_url {
+        bedrock = bedrock.with_endpoint(base_url);
+    }
+    if let Some(control_url) = &provider.control_url {
+        bedrock = bedrock.with_control_endpoint(control_url);
+    }
+    let client = claude_client::ClaudeClient::new(String::new())
+        .with_instance(name)
+        .with_models(provider.models.clone())
+        .with_bedrock(bedrock);
+    Ok(Box::new(client))
+}
+
+/// コマンドライン引数に応じてセッションを開く
+fn open_session(args: &Args, store: &SessionStore) -> Result<Session> {
+    if let Some(name) = &args.session {
+        if store.exists(name) {
+            return store.load(name);
+        }
+        return Ok(Session::new(name));
+    }
+
+    if args.resume {
+        return picker::select_session_input(store);
+    }
+
+    Ok(Session::new_unnamed())
 }
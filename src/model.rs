@@ -1,15 +1,73 @@
-use std::fmt;
+use std::{collections::BTreeMap, fmt, sync::OnceLock};
 
-#[derive(Debug, Clone)]
+use serde::{Deserialize, Serialize};
+
+use crate::tokens::Tokenizer;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Model {
     pub name: String,
     pub campany: Campany,
+    /// 設定ファイルの `[providers.<name>]` で追加した接続先の名前。組み込みの接続先の場合は `None`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Campany {
+    #[serde(alias = "openai")]
     OpenAI,
+    #[serde(alias = "claude", alias = "anthropic")]
     Claude,
+    #[serde(alias = "ollama")]
+    Ollama,
+    #[serde(alias = "gemini", alias = "google")]
+    Gemini,
+}
+
+impl Model {
+    /// モデル名からベンダーを推測してModelを作る
+    ///
+    /// `--model` で指定されたモデル名のように、一覧を取得せずにモデルを決める場合に使う。
+    pub fn from_name(name: &str) -> Self {
+        let campany = if name.starts_with("claude") {
+            Campany::Claude
+        } else if name.starts_with("gemini") {
+            Campany::Gemini
+        } else {
+            Campany::OpenAI
+        };
+        Self::new(name, campany)
+    }
+
+    pub fn new(name: &str, campany: Campany) -> Self {
+        Self {
+            name: name.to_owned(),
+            campany,
+            provider: None,
+        }
+    }
+
+    /// 設定ファイルで追加した接続先 `provider` のモデルを作る
+    pub fn with_provider(name: &str, campany: Campany, provider: &str) -> Self {
+        Self {
+            provider: Some(provider.to_owned()),
+            ..Self::new(name, campany)
+        }
+    }
+
+    /// モデル選択の画面などに表示する、ベンダーまたは接続先の名前
+    pub fn vendor(&self) -> String {
+        match &self.provider {
+            Some(provider) => provider.clone(),
+            None => self.campany.to_string(),
+        }
+    }
+
+    /// モデルの性能や対応しているパラメータ
+    pub fn capabilities(&self) -> ModelCapabilities {
+        registry().lookup(self)
+    }
 }
 
 // Modelを表示するための実装
@@ -25,3 +83,760 @@ impl From<Model> for String {
         model.name
     }
 }
+
+impl Campany {
+    /// 全てのベンダー。モデルの選択肢はこの順に並ぶ。
+    pub const ALL: [Campany; 4] = [
+        Campany::Claude,
+        Campany::OpenAI,
+        Campany::Gemini,
+        Campany::Ollama,
+    ];
+
+    /// APIキーを読み込む環境変数の名前
+    ///
+    /// OllamaはAPIキーが不要なので、代わりに接続先のホストを読み込む。
+    pub fn api_key_env(&self) -> &'static str {
+        match *self {
+            Campany::OpenAI => "OPENAI_API_KEY",
+            Campany::Claude => "ANTHROPIC_API_KEY",
+            Campany::Ollama => "OLLAMA_HOST",
+            Campany::Gemini => "GEMINI_API_KEY",
+        }
+    }
+
+    /// 設定ファイルでベンダーを指す名前（`openai`、`claude` など）からCampanyを返す
+    pub fn from_config_name(name: &str) -> Option<Campany> {
+        serde_json::from_value(serde_json::Value::String(name.to_string())).ok()
+    }
+}
+
+// Campanyを表示するための実装
+impl fmt::Display for Campany {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Campany::OpenAI => write!(f, "ChatGPT"),
+            Campany::Claude => write!(f, "Claude"),
+            Campany::Ollama => write!(f, "Ollama"),
+            Campany::Gemini => write!(f, "Gemini"),
+        }
+    }
+}
+
+/// システムプロンプトの送り方
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SystemRole {
+    /// `system` ロールのメッセージとして送る
+    System,
+    /// `developer` ロールのメッセージとして送る（OpenAIの推論モデル）
+    Developer,
+    /// bodyのトップレベルの `system` に入れる（Claude）
+    TopLevel,
+    /// 対応していないので、最初の質問の前に連結する
+    Unsupported,
+}
+
+/// モデルが扱える入力の種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Modality {
+    Text,
+    Image,
+    Audio,
+}
+
+/// 100万トークンあたりの料金（USD）
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Pricing {
+    pub input: f64,
+    pub output: f64,
+    /// キャッシュされた入力トークンの料金
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cached_input: Option<f64>,
+}
+
+/// モデルの性能や対応しているパラメータ
+///
+/// リクエストの形（ストリーミングの有無、システムプロンプトのロール、送信できるパラメータなど）は、
+/// モデル名から推測せずにこの情報を元に決める。
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModelCapabilities {
+    /// コンテキストウィンドウのトークン数
+    pub context_window: u32,
+    /// 回答の最大トークン数
+    pub max_output_tokens: Option<u32>,
+    /// ストリーミングに対応しているか
+    pub streaming: bool,
+    /// 推論モデルか。OpenAIでは `max_tokens` の代わりに `max_completion_tokens` を使う
+    pub reasoning: bool,
+    pub system_role: SystemRole,
+    /// 送信できる生成パラメータ
+    pub temperature: bool,
+    pub max_temperature: f32,
+    pub top_p: bool,
+    pub stop: bool,
+    pub seed: bool,
+    pub modalities: Vec<Modality>,
+    /// tool use（function calling）に対応しているか
+    pub tools: bool,
+    pub pricing: Option<Pricing>,
+    /// トークン数の数え方
+    #[serde(default)]
+    pub tokenizer: Tokenizer,
+}
+
+impl ModelCapabilities {
+    /// OpenAIのチャットモデルの既定値
+    fn openai_chat(context_window: u32, max_output_tokens: u32, pricing: Pricing) -> Self {
+        Self {
+            context_window,
+            max_output_tokens: Some(max_output_tokens),
+            streaming: true,
+            reasoning: false,
+            system_role: SystemRole::System,
+            temperature: true,
+            max_temperature: 2.0,
+            top_p: true,
+            stop: true,
+            seed: true,
+            modalities: vec![Modality::Text, Modality::Image],
+            tools: true,
+            pricing: Some(pricing),
+            tokenizer: Tokenizer::O200kBase,
+        }
+    }
+
+    /// OpenAIの推論モデルの既定値。temperatureやtop_pは送信できない。
+    fn openai_reasoning(context_window: u32, max_output_tokens: u32, pricing: Pricing) -> Self {
+        Self {
+            reasoning: true,
+            system_role: SystemRole::Developer,
+            temperature: false,
+            top_p: false,
+            stop: false,
+            ..Self::openai_chat(context_window, max_output_tokens, pricing)
+        }
+    }
+
+    /// Claudeのモデルの既定値
+    fn claude(max_output_tokens: u32, pricing: Pricing) -> Self {
+        Self {
+            context_window: 200_000,
+            max_output_tokens: Some(max_output_tokens),
+            streaming: true,
+            reasoning: false,
+            system_role: SystemRole::TopLevel,
+            temperature: true,
+            max_temperature: 1.0,
+            top_p: true,
+            stop: true,
+            seed: false,
+            modalities: vec![Modality::Text, Modality::Image],
+            tools: true,
+            pricing: Some(pricing),
+            tokenizer: Tokenizer::Claude,
+        }
+    }
+
+    /// Geminiのモデルの既定値
+    ///
+    /// システムプロンプトは `systemInstruction` に入れる。
+    /// トークナイザーは公開されていないので、o200k_baseで近似する。
+    fn gemini(context_window: u32, max_output_tokens: u32, pricing: Pricing) -> Self {
+        Self {
+            context_window,
+            max_output_tokens: Some(max_output_tokens),
+            streaming: true,
+            reasoning: false,
+            system_role: SystemRole::TopLevel,
+            temperature: true,
+            max_temperature: 2.0,
+            top_p: true,
+            stop: true,
+            seed: true,
+            modalities: vec![Modality::Text, Modality::Image, Modality::Audio],
+            tools: true,
+            pricing: Some(pricing),
+            tokenizer: Tokenizer::O200kBase,
+        }
+    }
+
+    /// Ollamaのモデルの既定値
+    ///
+    /// コンテキストウィンドウはモデルではなくOllamaの `num_ctx` の既定値。
+    /// トークナイザーはモデルごとに異なるので、o200k_baseで近似する。
+    fn ollama() -> Self {
+        Self {
+            context_window: 4_096,
+            max_output_tokens: None,
+            streaming: true,
+            reasoning: false,
+            system_role: SystemRole::System,
+            temperature: true,
+            max_temperature: 2.0,
+            top_p: true,
+            stop: true,
+            seed: true,
+            modalities: vec![Modality::Text],
+            tools: false,
+            pricing: None,
+            tokenizer: Tokenizer::O200kBase,
+        }
+    }
+
+    /// 登録されていないモデルに使う値
+    fn fallback(campany: Campany) -> Self {
+        let mut capabilities = match campany {
+            Campany::OpenAI => Self::openai_chat(128_000, 4_096, price(0.0, 0.0, None)),
+            Campany::Claude => Self::claude(4_096, price(0.0, 0.0, None)),
+            Campany::Ollama => Self::ollama(),
+            Campany::Gemini => Self::gemini(1_048_576, 8_192, price(0.0, 0.0, None)),
+        };
+        capabilities.pricing = None;
+        capabilities
+    }
+}
+
+impl ModelCapabilities {
+    /// モデル選択の画面に表示する、性能の短い説明
+    ///
+    /// 例: `200K ctx  $3.00/$15.00  画像 ツール`
+    pub fn summary(&self) -> String {
+        let mut parts = vec![format_context_window(self.context_window)];
+        if let Some(pricing) = &self.pricing {
+            parts.push(format!("${:.2}/${:.2}", pricing.input, pricing.output));
+        }
+        let mut features = Vec::new();
+        if self.modalities.contains(&Modality::Image) {
+            features.push("画像");
+        }
+        if self.tools {
+            features.push("ツール");
+        }
+        if self.reasoning {
+            features.push("推論");
+        }
+        if !features.is_empty() {
+            parts.push(features.join(" "));
+        }
+        parts.join("  ")
+    }
+}
+
+// コンテキストウィンドウのトークン数を `128K ctx` や `1M ctx` の形にする
+fn format_context_window(tokens: u32) -> String {
+    if tokens >= 1_000_000 {
+        format!("{}M ctx", tokens / 1_000_000)
+    } else {
+        format!("{}K ctx", tokens / 1_000)
+    }
+}
+
+fn price(input: f64, output: f64, cached_input: Option<f64>) -> Pricing {
+    Pricing {
+        input,
+        output,
+        cached_input,
+    }
+}
+
+/// OpenAIのモデル一覧のうち、チャットに使えないモデルの名前に含まれる語
+///
+/// `-` で区切った語と完全に一致する場合だけ除く。`gpt-4o-search-preview` や
+/// `gpt-4o-audio-preview` はチャットに使えるので、`search` や `audio` は含めない。
+const NON_CHAT_WORDS: &[&str] = &[
+    "embedding",
+    "tts",
+    "whisper",
+    "dall",
+    "image",
+    "realtime",
+    "transcribe",
+    "moderation",
+    "instruct",
+    "davinci",
+    "babbage",
+    "sora",
+];
+
+/// OpenAIのモデル一覧のうち、チャットに使えるモデルかどうか
+///
+/// チャットに使えないモデルだけを名前で除く。レジストリにない新しいモデルは、既定の性能で使えるように残す。
+pub fn is_openai_chat_model(name: &str) -> bool {
+    !name.split('-').any(|word| NON_CHAT_WORDS.contains(&word))
+}
+
+/// モデルの性能の一覧
+///
+/// モデル名の前方一致で検索し、最も長く一致したエントリを使う。
+/// 組み込みの一覧は設定ファイルの `[models."<prefix>"]` で上書きできる。
+#[derive(Debug, Clone)]
+pub struct ModelRegistry {
+    entries: Vec<(String, Campany, ModelCapabilities)>,
+    /// モデルの別名。候補のうち、有効なベンダーの最初のモデルを使う
+    aliases: BTreeMap<String, Vec<String>>,
+    /// 設定ファイルで追加した接続先。`<接続先>:<モデル名>` の形でモデルを指定できる
+    providers: BTreeMap<String, Campany>,
+}
+
+/// 組み込みのモデルの別名
+const BUILTIN_ALIASES: &[(&str, &[&str])] = &[
+    (
+        "fast",
+        &["claude-haiku-4-5", "gpt-4.1-mini", "gemini-2.5-flash"],
+    ),
+    ("smart", &["claude-sonnet-4-5", "gpt-4.1", "gemini-2.5-pro"]),
+];
+
+impl ModelRegistry {
+    /// 組み込みのモデル一覧
+    pub fn builtin() -> Self {
+        use Campany::{Claude, Gemini, OpenAI};
+        type Caps = ModelCapabilities;
+
+        let mut o1_mini = Caps::openai_reasoning(128_000, 65_536, price(1.10, 4.40, Some(0.55)));
+        o1_mini.streaming = false;
+        o1_mini.system_role = SystemRole::Unsupported;
+        o1_mini.modalities = vec![Modality::Text];
+        o1_mini.tools = false;
+        let mut o1 = Caps::openai_reasoning(200_000, 100_000, price(15.0, 60.0, Some(7.50)));
+        o1.streaming = false;
+        let mut gpt5 = Caps::openai_reasoning(400_000, 128_000, price(1.25, 10.0, Some(0.125)));
+        gpt5.stop = false;
+        let mut gpt35 = Caps::openai_chat(16_385, 4_096, price(0.50, 1.50, None));
+        gpt35.modalities = vec![Modality::Text];
+        gpt35.tokenizer = Tokenizer::Cl100kBase;
+        let mut gpt4 = Caps::openai_chat(8_192, 8_192, price(30.0, 60.0, None));
+        gpt4.modalities = vec![Modality::Text];
+        gpt4.tokenizer = Tokenizer::Cl100kBase;
+        let mut gpt4_turbo = Caps::openai_chat(128_000, 4_096, price(10.0, 30.0, None));
+        gpt4_turbo.tokenizer = Tokenizer::Cl100kBase;
+
+        let entries = vec![
+            ("gpt-3.5-turbo", OpenAI, gpt35),
+            ("gpt-4", OpenAI, gpt4),
+            ("gpt-4-turbo", OpenAI, gpt4_turbo.clone()),
+            ("gpt-4-1106", OpenAI, gpt4_turbo.clone()),
+            ("gpt-4-0125", OpenAI, gpt4_turbo),
+            (
+                "gpt-4o",
+                OpenAI,
+                Caps::openai_chat(128_000, 16_384, price(2.50, 10.0, Some(1.25))),
+            ),
+            (
+                "gpt-4o-mini",
+                OpenAI,
+                Caps::openai_chat(128_000, 16_384, price(0.15, 0.60, Some(0.075))),
+            ),
+            (
+                "gpt-4.1",
+                OpenAI,
+                Caps::openai_chat(1_047_576, 32_768, price(2.0, 8.0, Some(0.50))),
+            ),
+            (
+                "gpt-4.1-mini",
+                OpenAI,
+                Caps::openai_chat(1_047_576, 32_768, price(0.40, 1.60, Some(0.10))),
+            ),
+            (
+                "gpt-4.1-nano",
+                OpenAI,
+                Caps::openai_chat(1_047_576, 32_768, price(0.10, 0.40, Some(0.025))),
+            ),
+            ("gpt-5", OpenAI, gpt5.clone()),
+            (
+                "gpt-5-mini",
+                OpenAI,
+                Caps {
+                    pricing: Some(price(0.25, 2.0, Some(0.025))),
+                    ..gpt5.clone()
+                },
+            ),
+            (
+                "gpt-5-nano",
+                OpenAI,
+                Caps {
+                    pricing: Some(price(0.05, 0.40, Some(0.005))),
+                    ..gpt5
+                },
+            ),
+            ("o1", OpenAI, o1),
+            ("o1-preview", OpenAI, o1_mini.clone()),
+            ("o1-mini", OpenAI, o1_mini),
+            (
+                "o3",
+                OpenAI,
+                Caps::openai_reasoning(200_000, 100_000, price(2.0, 8.0, Some(0.50))),
+            ),
+            (
+                "o3-mini",
+                OpenAI,
+                Caps::openai_reasoning(200_000, 100_000, price(1.10, 4.40, Some(0.55))),
+            ),
+            (
+                "o4-mini",
+                OpenAI,
+                Caps::openai_reasoning(200_000, 100_000, price(1.10, 4.40, Some(0.275))),
+            ),
+            (
+                "claude-3-haiku",
+                Claude,
+                Caps::claude(4_096, price(0.25, 1.25, Some(0.03))),
+            ),
+            (
+                "claude-3-sonnet",
+                Claude,
+                Caps::claude(4_096, price(3.0, 15.0, Some(0.30))),
+            ),
+            (
+                "claude-3-opus",
+                Claude,
+                Caps::claude(4_096, price(15.0, 75.0, Some(1.50))),
+            ),
+            (
+                "claude-3-5-haiku",
+                Claude,
+                Caps::claude(8_192, price(0.80, 4.0, Some(0.08))),
+            ),
+            (
+                "claude-3-5-sonnet",
+                Claude,
+                Caps::claude(8_192, price(3.0, 15.0, Some(0.30))),
+            ),
+            (
+                "claude-3-7-sonnet",
+                Claude,
+                Caps::claude(64_000, price(3.0, 15.0, Some(0.30))),
+            ),
+            (
+                "claude-sonnet-4",
+                Claude,
+                Caps::claude(64_000, price(3.0, 15.0, Some(0.30))),
+            ),
+            (
+                "claude-opus-4",
+                Claude,
+                Caps::claude(32_000, price(15.0, 75.0, Some(1.50))),
+            ),
+            (
+                "claude-haiku-4-5",
+                Claude,
+                Caps::claude(64_000, price(1.0, 5.0, Some(0.10))),
+            ),
+            (
+                "claude-opus-4-5",
+                Claude,
+                Caps::claude(64_000, price(5.0, 25.0, Some(0.50))),
+            ),
+            (
+                "gemini-2.0-flash",
+                Gemini,
+                Caps::gemini(1_048_576, 8_192, price(0.10, 0.40, Some(0.025))),
+            ),
+            (
+                "gemini-2.0-flash-lite",
+                Gemini,
+                Caps::gemini(1_048_576, 8_192, price(0.075, 0.30, None)),
+            ),
+            (
+                "gemini-2.5-pro",
+                Gemini,
+                Caps {
+                    reasoning: true,
+                    ..Caps::gemini(1_048_576, 65_536, price(1.25, 10.0, Some(0.31)))
+                },
+            ),
+            (
+                "gemini-2.5-flash",
+                Gemini,
+                Caps {
+                    reasoning: true,
+                    ..Caps::gemini(1_048_576, 65_536, price(0.30, 2.50, Some(0.075)))
+                },
+            ),
+            (
+                "gemini-2.5-flash-lite",
+                Gemini,
+                Caps::gemini(1_048_576, 65_536, price(0.10, 0.40, Some(0.025))),
+            ),
+        ];
+
+        Self {
+            entries: entries
+                .into_iter()
+                .map(|(prefix, campany, caps)| (prefix.to_string(), campany, caps))
+                .collect(),
+            aliases: BUILTIN_ALIASES
+                .iter()
+                .map(|(alias, names)| {
+                    (
+                        alias.to_string(),
+                        names.iter().map(|n| n.to_string()).collect(),
+                    )
+                })
+                .collect(),
+            providers: BTreeMap::new(),
+        }
+    }
+
+    /// 設定ファイルで追加した接続先を登録する
+    pub fn add_providers(&mut self, providers: &BTreeMap<String, Campany>) {
+        self.providers.extend(providers.clone());
+    }
+
+    /// 設定ファイルの別名を追加する。組み込みの別名と同じ名前の場合は置き換える。
+    pub fn add_aliases(&mut self, aliases: &BTreeMap<String, String>) {
+        for (alias, name) in aliases {
+            self.aliases.insert(alias.clone(), vec![name.clone()]);
+        }
+    }
+
+    /// モデル名または別名からModelを作る
+    ///
+    /// 別名の候補のうち、`enabled` に含まれるベンダーのモデルを優先する。
+    /// `<接続先>:<モデル名>` の形の場合は、設定ファイルで追加した接続先のモデルを作る。
+    pub fn resolve(&self, name: &str, enabled: &[Campany]) -> Model {
+        let Some(candidates) = self.aliases.get(name) else {
+            return self.parse(name);
+        };
+        let models: Vec<Model> = candidates.iter().map(|n| self.parse(n)).collect();
+        models
+            .iter()
+            .find(|m| enabled.contains(&m.campany))
+            .or(models.first())
+            .cloned()
+            .unwrap_or_else(|| self.parse(name))
+    }
+
+    // `llama3:8b` のようにモデル名自体に `:` を含む場合があるので、
+    // 接頭辞が登録済みの接続先かベンダーの名前（`ollama:llama3:8b` など）である場合だけ分ける
+    fn parse(&self, name: &str) -> Model {
+        if let Some((provider, model)) = name.split_once(':') {
+            if let Some(campany) = self.providers.get(provider) {
+                return Model::with_provider(model, *campany, provider);
+            }
+            if let Some(campany) = Campany::from_config_name(provider) {
+                return Model::new(model, campany);
+            }
+        }
+        Model::from_name(name)
+    }
+
+    /// 設定ファイルの内容で上書きする
+    ///
+    /// 既存のエントリは指定された項目だけを上書きし、存在しないエントリは `provider` と全ての項目を指定して追加する。
+    pub fn apply_overrides(
+        &mut self,
+        overrides: &BTreeMap<String, serde_json::Value>,
+    ) -> anyhow::Result<()> {
+        for (prefix, value) in overrides {
+            let existing = self.entries.iter().position(|(p, _, _)| p == prefix);
+            let (campany, mut base) = match existing {
+                Some(i) => {
+                    let (_, campany, caps) = &self.entries[i];
+                    (*campany, serde_json::to_value(caps)?)
+                }
+                None => {
+                    let campany = match value.get("provider") {
+                        Some(provider) => serde_json::from_value(provider.clone())?,
+                        None => Model::from_name(prefix).campany,
+                    };
+                    (
+                        campany,
+                        serde_json::to_value(ModelCapabilities::fallback(campany))?,
+                    )
+                }
+            };
+
+            if let (Some(base), Some(value)) = (base.as_object_mut(), value.as_object()) {
+                for (key, v) in value {
+                    if key != "provider" {
+                        base.insert(key.clone(), v.clone());
+                    }
+                }
+            }
+            let caps: ModelCapabilities = serde_json::from_value(base)
+                .map_err(|e| anyhow::anyhow!("invalid model settings for {}: {}", prefix, e))?;
+
+            match existing {
+                Some(i) => self.entries[i] = (prefix.clone(), campany, caps),
+                None => self.entries.push((prefix.clone(), campany, caps)),
+            }
+        }
+        Ok(())
+    }
+
+    /// モデルの性能を返す。登録されていないモデルはベンダーごとの既定値を返す。
+    pub fn lookup(&self, model: &Model) -> ModelCapabilities {
+        let name = match model.campany {
+            Campany::Claude => bedrock_base_name(&model.name),
+            _ => &model.name,
+        };
+        self.find(name, model.campany)
+            .cloned()
+            .unwrap_or_else(|| ModelCapabilities::fallback(model.campany))
+    }
+
+    fn find(&self, name: &str, campany: Campany) -> Option<&ModelCapabilities> {
+        self.entries
+            .iter()
+            .filter(|(prefix, c, _)| *c == campany && name.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _, _)| prefix.len())
+            .map(|(_, _, caps)| caps)
+    }
+}
+
+// BedrockのモデルID（`us.anthropic.claude-3-5-sonnet-20240620-v1:0`）から、
+// 推論プロファイルの地域と `anthropic.` を取り除いてAnthropicのモデル名に揃える
+fn bedrock_base_name(name: &str) -> &str {
+    name.split_once("anthropic.").map_or(name, |(_, rest)| rest)
+}
+
+static REGISTRY: OnceLock<ModelRegistry> = OnceLock::new();
+
+/// 起動時に設定ファイルの内容を反映したレジストリをセットする
+///
+/// 2回目以降の呼び出しは無視される。
+pub fn init_registry(
+    overrides: &BTreeMap<String, serde_json::Value>,
+    aliases: &BTreeMap<String, String>,
+    providers: &BTreeMap<String, Campany>,
+) -> anyhow::Result<()> {
+    let mut registry = ModelRegistry::builtin();
+    registry.apply_overrides(overrides)?;
+    registry.add_aliases(aliases);
+    registry.add_providers(providers);
+    let _ = REGISTRY.set(registry);
+    Ok(())
+}
+
+/// 利用中のモデルレジストリ。セットされていない場合は組み込みの一覧を使う。
+pub fn registry() -> &'static ModelRegistry {
+    REGISTRY.get_or_init(ModelRegistry::builtin)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_openai_chat_models() {
+        for name in [
+            "gpt-4o-2024-08-06",
+            "gpt-4o-search-preview",
+            "gpt-4o-mini-search-preview-2025-03-11",
+            "gpt-4o-audio-preview",
+            "gpt-4o-mini-audio-preview",
+            "chatgpt-4o-latest",
+            "o3-mini",
+        ] {
+            assert!(is_openai_chat_model(name), "{}", name);
+        }
+        for name in [
+            "gpt-4o-realtime-preview",
+            "text-embedding-3-small",
+            "tts-1-hd",
+            "gpt-4o-mini-tts",
+            "whisper-1",
+            "dall-e-3",
+            "gpt-image-1",
+            "gpt-4o-transcribe",
+            "omni-moderation-latest",
+            "gpt-3.5-turbo-instruct",
+            "davinci-002",
+            "babbage-002",
+        ] {
+            assert!(!is_openai_chat_model(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn lookup_by_longest_prefix() {
+        let registry = ModelRegistry::builtin();
+
+        let mini = registry.lookup(&Model::from_name("gpt-4o-mini-2024-07-18"));
+        assert_eq!(mini.pricing.unwrap().input, 0.15);
+
+        let o1_mini = registry.lookup(&Model::from_name("o1-mini"));
+        assert!(!o1_mini.streaming);
+        assert_eq!(o1_mini.system_role, SystemRole::Unsupported);
+
+        let claude = registry.lookup(&Model::from_name("claude-3-5-sonnet-20240620"));
+        assert_eq!(claude.system_role, SystemRole::TopLevel);
+        assert!(!claude.seed);
+
+        assert!(is_openai_chat_model("gpt-9-preview"));
+        assert_eq!(
+            registry.lookup(&Model::from_name("gpt-9-preview")),
+            ModelCapabilities::fallback(Campany::OpenAI)
+        );
+    }
+
+    #[test]
+    fn resolve_alias() {
+        let registry = ModelRegistry::builtin();
+        assert_eq!(
+            registry.resolve("fast", &[Campany::OpenAI]).name,
+            "gpt-4.1-mini"
+        );
+        assert_eq!(
+            registry
+                .resolve("smart", &[Campany::Claude, Campany::OpenAI])
+                .campany,
+            Campany::Claude
+        );
+        assert_eq!(registry.resolve("gpt-4o", &[]).name, "gpt-4o");
+    }
+
+    #[test]
+    fn resolve_configured_provider() {
+        let mut registry = ModelRegistry::builtin();
+        registry.add_providers(&BTreeMap::from([("local".to_string(), Campany::OpenAI)]));
+
+        let model = registry.resolve("local:llama3:8b", &[]);
+        assert_eq!(model.name, "llama3:8b");
+        assert_eq!(model.provider.as_deref(), Some("local"));
+        assert_eq!(model.vendor(), "local");
+
+        let model = registry.resolve("llama3:8b", &[]);
+        assert_eq!(model.name, "llama3:8b");
+        assert_eq!(model.provider, None);
+
+        let model = registry.resolve("ollama:llama3:8b", &[]);
+        assert_eq!(model.name, "llama3:8b");
+        assert_eq!(model.campany, Campany::Ollama);
+
+        registry.add_providers(&BTreeMap::from([("bedrock".to_string(), Campany::Claude)]));
+        let model = registry.resolve("bedrock:us.anthropic.claude-3-5-sonnet-20240620-v1:0", &[]);
+        assert_eq!(model.name, "us.anthropic.claude-3-5-sonnet-20240620-v1:0");
+        assert_eq!(model.campany, Campany::Claude);
+        assert_eq!(
+            registry.lookup(&model).pricing,
+            registry
+                .lookup(&Model::from_name("claude-3-5-sonnet-20240620"))
+                .pricing
+        );
+    }
+
+    #[test]
+    fn override_from_config() {
+        let mut registry = ModelRegistry::builtin();
+        let overrides: BTreeMap<String, serde_json::Value> = serde_json::from_str(
+            r#"{
+                "gpt-4o": {"context_window": 64000},
+                "llama3": {"provider": "openai", "context_window": 8192, "seed": false}
+            }"#,
+        )
+        .unwrap();
+        registry.apply_overrides(&overrides).unwrap();
+
+        let gpt = registry.lookup(&Model::from_name("gpt-4o"));
+        assert_eq!(gpt.context_window, 64000);
+        assert_eq!(gpt.pricing.unwrap().output, 10.0);
+
+        let llama = registry.lookup(&Model::from_name("llama3:8b"));
+        assert_eq!(llama.context_window, 8192);
+        assert!(!llama.seed);
+    }
+}
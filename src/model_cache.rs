@@ -0,0 +1,119 @@
+use std::{fs, path::PathBuf};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, Local};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{model::Model, session::data_dir};
+
+/// モデル一覧のキャッシュの有効期間
+pub const DEFAULT_TTL_HOURS: i64 = 24;
+
+/// APIから取得したモデル一覧のキャッシュ
+///
+/// `<data_dir>/cache/models-<name>.json` に保存する。
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ModelCache {
+    pub fetched_at: DateTime<Local>,
+    pub models: Vec<Model>,
+}
+
+impl ModelCache {
+    pub fn new(models: Vec<Model>) -> Self {
+        Self {
+            fetched_at: Local::now(),
+            models,
+        }
+    }
+
+    /// 取得してから有効期間が過ぎていないかどうか
+    pub fn is_fresh(&self, ttl: Duration) -> bool {
+        Local::now() - self.fetched_at < ttl
+    }
+
+    /// キャッシュを読み込む。存在しない、または壊れている場合は `None` を返す。
+    pub fn load(name: &str) -> Option<Self> {
+        let path = cache_path(name).ok()?;
+        let json = fs::read_to_string(path).ok()?;
+        serde_json::from_str(&json).ok()
+    }
+
+    pub fn save(&self, name: &str) -> Result<()> {
+        let path = cache_path(name)?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&path, serde_json::to_string(self)?)
+            .with_context(|| format!("failed to write {}", path.display()))
+    }
+}
+
+/// キャッシュが新しければそれを使い、古ければ `fetch` で取得し直す
+///
+/// 取得に失敗した場合は、古いキャッシュ、`fallback` の順に使う。
+pub fn cached_models(
+    name: &str,
+    ttl: Duration,
+    fetch: impl FnOnce() -> Result<Vec<Model>>,
+    fallback: impl FnOnce() -> Vec<Model>,
+) -> Vec<Model> {
+    let cache = ModelCache::load(name);
+    if let Some(cache) = &cache {
+        if cache.is_fresh(ttl) && !cache.models.is_empty() {
+            return cache.models.clone();
+        }
+    }
+
+    match fetch() {
+        Ok(models) if !models.is_empty() => {
+            // キャッシュの保存に失敗しても、取得したモデル一覧はそのまま使う
+            let _ = ModelCache::new(models.clone()).save(name);
+            models
+        }
+        _ => match cache {
+            Some(cache) if !cache.models.is_empty() => cache.models,
+            _ => fallback(),
+        },
+    }
+}
+
+/// ベンダー名とベースURLから、`cached_models` に渡すキャッシュの名前を作る
+///
+/// ベースURLを変えた場合に、別の接続先のモデル一覧を使ってしまわないようにする。
+pub fn cache_name(vendor: &str, base_url: &str) -> String {
+    let digest = Sha256::digest(base_url.trim_end_matches('/').as_bytes());
+    let hash: String = digest[..6].iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}-{}", vendor, hash)
+}
+
+fn cache_path(name: &str) -> Result<PathBuf> {
+    Ok(data_dir()?
+        .join("cache")
+        .join(format!("models-{}.json", name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_freshness() {
+        let mut cache = ModelCache::new(vec![Model::from_name("claude-3-haiku-20240307")]);
+        assert!(cache.is_fresh(Duration::hours(DEFAULT_TTL_HOURS)));
+
+        cache.fetched_at = Local::now() - Duration::hours(DEFAULT_TTL_HOURS + 1);
+        assert!(!cache.is_fresh(Duration::hours(DEFAULT_TTL_HOURS)));
+
+        let default = cache_name("claude", "https://api.anthropic.com/v1");
+        assert_eq!(
+            default,
+            cache_name("claude", "https://api.anthropic.com/v1/")
+        );
+        assert_ne!(
+            default,
+            cache_name("claude", "https://proxy.example.com/v1")
+        );
+        assert!(default.starts_with("claude-"));
+    }
+}
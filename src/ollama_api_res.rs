@@ -0,0 +1,97 @@
+use serde::Deserialize;
+
+use crate::usage::Usage;
+
+// ローカルにあるモデルの一覧は、下記のようなデータが返ってくる
+//
+// ```json
+// {
+//   "models": [
+//     {
+//       "name": "llama3.2:latest",
+//       "model": "llama3.2:latest",
+//       "modified_at": "2024-12-07T13:05:42.193924+09:00",
+//       "size": 2019393189,
+//       "digest": "a80c4f17acd55265feec403c7aef86be0c25983ab279d83f3bcd3abbcb5b8b72",
+//       "details": {"format": "gguf", "family": "llama", "parameter_size": "3.2B", "quantization_level": "Q4_K_M"}
+//     }
+//   ]
+// }
+// ```
+#[derive(Debug, Deserialize)]
+pub struct Tags {
+    pub models: Vec<LocalModel>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LocalModel {
+    pub name: String,
+    #[serde(default)]
+    pub modified_at: String,
+    #[serde(default)]
+    pub size: u64,
+}
+
+// `/api/chat` のストリーミングでは、1行に1つのJSONが返ってくる
+//
+// ```
+// {"model":"llama3.2","created_at":"2024-12-07T04:06:17.51Z","message":{"role":"assistant","content":"こんに"},"done":false}
+// {"model":"llama3.2","created_at":"2024-12-07T04:06:17.53Z","message":{"role":"assistant","content":"ちは"},"done":false}
+// {"model":"llama3.2","created_at":"2024-12-07T04:06:17.55Z","message":{"role":"assistant","content":""},"done":true,"done_reason":"stop","prompt_eval_count":26,"eval_count":12}
+// ```
+//
+// 生成の途中でエラーになった場合は `{"error":"..."}` が返ってくる。
+#[derive(Debug, Deserialize)]
+pub struct ChatChunk {
+    #[serde(default)]
+    pub message: Option<ChatMessage>,
+    #[serde(default)]
+    pub done: bool,
+    #[serde(default)]
+    pub done_reason: Option<String>,
+    /// 入力のトークン数。最後の行にだけ含まれる
+    #[serde(default)]
+    pub prompt_eval_count: Option<u64>,
+    /// 出力のトークン数。最後の行にだけ含まれる
+    #[serde(default)]
+    pub eval_count: Option<u64>,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: String,
+}
+
+impl From<&ChatChunk> for Usage {
+    fn from(chunk: &ChatChunk) -> Self {
+        Usage {
+            input_tokens: chunk.prompt_eval_count.unwrap_or(0),
+            output_tokens: chunk.eval_count.unwrap_or(0),
+            cached_tokens: 0,
+        }
+    }
+}
+
+// `/api/pull` では、ダウンロードの進み具合が1行ずつ返ってくる
+//
+// ```
+// {"status":"pulling manifest"}
+// {"status":"pulling 6a0746a1ec1a","digest":"sha256:6a0746a1ec1a...","total":4661211424,"completed":241970}
+// {"status":"verifying sha256 digest"}
+// {"status":"writing manifest"}
+// {"status":"success"}
+// ```
+#[derive(Debug, Deserialize)]
+pub struct PullStatus {
+    #[serde(default)]
+    pub status: String,
+    #[serde(default)]
+    pub total: Option<u64>,
+    #[serde(default)]
+    pub completed: Option<u64>,
+    #[serde(default)]
+    pub error: Option<String>,
+}
@@ -0,0 +1,297 @@
+use anyhow::{anyhow, Result};
+use reqwest::blocking::Client;
+use serde_json::json;
+
+use crate::{
+    api_error::ApiError,
+    chat_message::MessageHistory,
+    http,
+    model::{Campany, Model},
+    ollama_api_res::{ChatChunk, PullStatus, Tags},
+    openai_client::openai_messages,
+    params::GenerationParams,
+    provider::{ChatProvider, PullProgress, StreamEvent},
+    usage::Usage,
+};
+
+/// OllamaのAPIのデフォルトのベースURL
+pub const DEFAULT_BASE_URL: &str = "http://localhost:11434";
+
+/// ローカルで動かすOllamaのクライアント
+///
+/// OpenAI互換のAPIではなく、Ollama独自の `/api/chat` を使う。
+/// 手元にないモデルを指定した場合は、ダウンロードしてから送信する。
+pub struct OllamaClient {
+    base_url: String,
+    model: Option<Model>,
+    client: Client,
+}
+
+impl OllamaClient {
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            model: None,
+            // モデルの読み込みやダウンロードには時間がかかるので、タイムアウトしないようにする
+            client: Client::builder()
+                .timeout(None)
+                .build()
+                .unwrap_or_else(|_| Client::new()),
+        }
+    }
+
+    // ローカルにあるモデルの一覧を取得する
+    // https://github.com/ollama/ollama/blob/main/docs/api.md#list-local-models
+    pub fn fetch_models(&self) -> Result<Vec<Model>> {
+        let url = format!("{}/api/tags", self.base_url);
+        let response = http::get_request(&self.client, &url, self.generate_headers())?;
+        let mut tags: Tags = response.json()?;
+        // 最近使ったモデルを先頭にする
+        tags.models
+            .sort_by(|a, b| b.modified_at.cmp(&a.modified_at));
+        Ok(tags
+            .models
+            .into_iter()
+            .map(|m| Model::new(&m.name, Campany::Ollama))
+            .collect())
+    }
+
+    /// モデルをダウンロードする。進み具合は `on_event` に逐次通知する。
+    // https://github.com/ollama/ollama/blob/main/docs/api.md#pull-a-model
+    pub fn pull_model(&self, name: &str, on_event: &mut dyn FnMut(StreamEvent)) -> Result<()> {
+        let url = format!("{}/api/pull", self.base_url);
+        let body = json!({"model": name, "stream": true});
+        let response = http::send_post_request(
+            &self.client,
+            &url,
+            self.generate_headers(),
+            body,
+            &mut |r| on_event(StreamEvent::Retry(r.clone())),
+        )?;
+
+        // 同じ内容の通知が続かないように、段階か割合が変わったときだけ通知する
+        let mut last: Option<PullProgress> = None;
+        for line in http::stream_lines(response) {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let status: PullStatus = serde_json::from_str(&line)?;
+            if let Some(error) = status.error {
+                return Err(anyhow!("{}をダウンロードできませんでした: {}", name, error));
+            }
+            let percent = match (status.completed, status.total) {
+                (Some(completed), Some(total)) if total > 0 => Some(completed * 100 / total),
+                _ => None,
+            };
+            let progress = PullProgress {
+                model: name.to_string(),
+                status: status.status,
+                percent,
+            };
+            if last.as_ref() != Some(&progress) {
+                on_event(StreamEvent::Pull(progress.clone()));
+                last = Some(progress);
+            }
+        }
+        Ok(())
+    }
+
+    fn generate_headers(&self) -> reqwest::header::HeaderMap {
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert(
+            "Content-Type",
+            reqwest::header::HeaderValue::from_static("application/json"),
+        );
+        headers
+    }
+
+    // APIへ送信するbodyを作成する。
+    //
+    // 生成パラメータは `options` にまとめて送る。`max_tokens` はOllamaでは `num_predict` になる。
+    fn generate_body_from_history(
+        &self,
+        message_history: &MessageHistory,
+        params: &GenerationParams,
+    ) -> serde_json::Value {
+        let model = self.model.as_ref().unwrap();
+        let messages = openai_messages(message_history, model.capabilities().system_role);
+
+        let mut json = json!({
+            "model": model.name,
+            "messages": messages,
+            "stream": true,
+        });
+
+        let mut options = serde_json::Map::new();
+        if let Some(temperature) = params.temperature {
+            options.insert("temperature".into(), json!(temperature));
+        }
+        if let Some(top_p) = params.top_p {
+            options.insert("top_p".into(), json!(top_p));
+        }
+        if let Some(max_tokens) = params.max_tokens {
+            options.insert("num_predict".into(), json!(max_tokens));
+        }
+        if !params.stop.is_empty() {
+            options.insert("stop".into(), json!(params.stop));
+        }
+        if let Some(seed) = params.seed {
+            options.insert("seed".into(), json!(seed));
+        }
+        if let Some(num_ctx) = params.num_ctx {
+            options.insert("num_ctx".into(), json!(num_ctx));
+        }
+        if !options.is_empty() {
+            json["options"] = serde_json::Value::Object(options);
+        }
+
+        // keep_aliveは `5m` のような時間か秒数。`-1` はずっと、`0` はすぐにメモリから降ろす
+        if let Some(keep_alive) = &params.keep_alive {
+            json["keep_alive"] = match keep_alive.parse::<i64>() {
+                Ok(seconds) => json!(seconds),
+                Err(_) => json!(keep_alive),
+            };
+        }
+
+        json
+    }
+
+    // read_chat_stream
+    //
+    // APIから1行に1つのJSONが送られてくるので、`message.content`を取得して逐次通知する。
+    // `done` が `true` の行にトークン数が含まれているので、通知して読み込みを終了する。
+    fn read_chat_stream(
+        &self,
+        response: reqwest::blocking::Response,
+        on_event: &mut dyn FnMut(StreamEvent),
+    ) -> Result<String> {
+        let mut joined_string = String::new();
+
+        for line in http::stream_lines(response) {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let chunk: ChatChunk = serde_json::from_str(&line)?;
+            if let Some(error) = &chunk.error {
+                return Err(anyhow!("Ollama: {}", error));
+            }
+
+            if let Some(message) = &chunk.message {
+                if !message.content.is_empty() {
+                    joined_string.push_str(&message.content);
+                    on_event(StreamEvent::Text(message.content.clone()));
+                }
+            }
+            if chunk.done {
+                on_event(StreamEvent::Usage(Usage::from(&chunk)));
+                break;
+            }
+        }
+
+        Ok(joined_string)
+    }
+}
+
+/// `OLLAMA_HOST` の値をベースURLにする
+///
+/// Ollamaと同じく、`0.0.0.0:11434` のようにスキームを省略した値も受け付ける。
+pub fn host_url(host: &str) -> String {
+    let host = host.trim().trim_end_matches('/');
+    if host.is_empty() {
+        DEFAULT_BASE_URL.to_string()
+    } else if host.contains("://") {
+        host.to_string()
+    } else {
+        format!("http://{}", host)
+    }
+}
+
+// モデルがローカルにない場合、Ollamaは404を返す
+fn is_model_not_found(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<ApiError>()
+        .is_some_and(|e| e.status == reqwest::StatusCode::NOT_FOUND)
+}
+
+impl ChatProvider for OllamaClient {
+    fn campany(&self) -> Campany {
+        Campany::Ollama
+    }
+
+    fn list_models(&self) -> Result<Vec<Model>> {
+        self.fetch_models()
+    }
+
+    fn set_model(&mut self, model: Model) {
+        self.model = Some(model);
+    }
+
+    fn model(&self) -> Option<&Model> {
+        self.model.as_ref()
+    }
+
+    fn send_messages(
+        &self,
+        message_history: &MessageHistory,
+        params: &GenerationParams,
+        on_event: &mut dyn FnMut(StreamEvent),
+    ) -> Result<String> {
+        let url = format!("{}/api/chat", self.base_url);
+        let body = self.generate_body_from_history(message_history, params);
+        let send = |on_event: &mut dyn FnMut(StreamEvent)| {
+            http::send_post_request(
+                &self.client,
+                &url,
+                self.generate_headers(),
+                body.clone(),
+                &mut |r| on_event(StreamEvent::Retry(r.clone())),
+            )
+        };
+
+        // 手元にないモデルは、ダウンロードしてから送り直す
+        let response = match send(on_event) {
+            Err(err) if is_model_not_found(&err) => {
+                let model = self.model.as_ref().unwrap();
+                self.pull_model(&model.name, on_event)?;
+                send(on_event)?
+            }
+            result => result?,
+        };
+        self.read_chat_stream(response, on_event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat_message::Role;
+
+    #[test]
+    fn chat_body_and_stream_chunks() {
+        let mut client = OllamaClient::new(&host_url("127.0.0.1:11434"));
+        assert_eq!(client.base_url, "http://127.0.0.1:11434");
+        client.set_model(Model::new("llama3.2", Campany::Ollama));
+
+        let mut history = MessageHistory::default();
+        history.set_system("Be brief.");
+        history.push(Role::User, "hi");
+        let mut params = GenerationParams::default();
+        params.set("max_tokens", "100").unwrap();
+        params.set("num_ctx", "8192").unwrap();
+        params.set("keep_alive", "-1").unwrap();
+
+        let body = client.generate_body_from_history(&history, &params);
+        assert_eq!(body["messages"][0]["role"], "system");
+        assert_eq!(body["options"]["num_predict"], 100);
+        assert_eq!(body["options"]["num_ctx"], 8192);
+        assert_eq!(body["keep_alive"], -1);
+
+        let last: ChatChunk = serde_json::from_str(
+            r#"{"model":"llama3.2","message":{"role":"assistant","content":""},"done":true,"prompt_eval_count":26,"eval_count":12}"#,
+        )
+        .unwrap();
+        let usage = Usage::from(&last);
+        assert_eq!((usage.input_tokens, usage.output_tokens), (26, 12));
+    }
+}
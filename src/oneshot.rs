@@ -0,0 +1,188 @@
+use std::io::{self, stdout, Write};
+
+use anyhow::{anyhow, Result};
+use chrono::Local;
+
+use crate::{
+    budget::{estimate_request, Budget, Spent},
+    cancel,
+    chat_message::{MessageHistory, Role},
+    context::ContextConfig,
+    model::Model,
+    params::GenerationParams,
+    provider::{send_with_fallback, ChatProvider, StreamEvent},
+    usage::{UsageLedger, UsageRecord, UsageTotals},
+};
+
+/// 対話せずに1回だけ質問を送信し、回答だけを標準出力に書き出す
+///
+/// シェルのパイプラインやMakefileから使うためのモードで、
+/// モデルの選択やプロンプトの表示は一切行わない。
+/// `chain` の先頭のモデルに送信し、送信できなかった場合は残りのモデルで順に送り直す。
+/// 質問がモデルのコンテキストウィンドウに収まらない場合は送信しない。
+#[allow(clippy::too_many_arguments)]
+pub fn run_oneshot(
+    providers: &mut [Box<dyn ChatProvider>],
+    chain: &[Model],
+    mut history: MessageHistory,
+    params: &GenerationParams,
+    prompt: &str,
+    context: &ContextConfig,
+    budget: &Budget,
+    force: bool,
+) -> Result<()> {
+    let model = chain
+        .first()
+        .ok_or_else(|| anyhow!("モデルが選択されていません"))?;
+    params.validate(model)?;
+
+    history.push(Role::User, prompt);
+
+    // 予算の上限に達するモデルには送信しない
+    let spent = if budget.is_empty() {
+        None
+    } else {
+        Some(Spent::load(
+            &UsageLedger::open_default()?,
+            UsageTotals::default(),
+        )?)
+    };
+    // フォールバック先のモデルでも、送信前に確認する
+    let mut before_send = |model: &Model| -> Result<()> {
+        check_tokens(&history, model, params, context)?;
+        let Some(spent) = &spent else {
+            return Ok(());
+        };
+        let estimate = estimate_request(&history, model);
+        for warning in budget.enforce(spent, &estimate, force)? {
+            eprintln!("warning: {}", warning);
+        }
+        Ok(())
+    };
+
+    let mut out = stdout().lock();
+    let mut write_error = None;
+    let mut usage = None;
+    let result = send_with_fallback(
+        providers,
+        chain,
+        &history,
+        params,
+        &mut before_send,
+        &mut |event| match event {
+            StreamEvent::Text(text) => {
+                if write_error.is_some() {
+                    return;
+                }
+                if let Err(e) = out.write_all(text.as_bytes()).and_then(|_| out.flush()) {
+                    // 書き出せなくなったら、残りの回答は受信せずに終える
+                    write_error = Some(e);
+                    cancel::request();
+                }
+            }
+            StreamEvent::Usage(u) => usage = Some(u),
+            // 標準出力は回答だけにするため、再試行などの通知は標準エラー出力に書く
+            StreamEvent::Retry(notice) => eprintln!("warning: {}", notice),
+            StreamEvent::Fallback(notice) => eprintln!("warning: {}", notice),
+            StreamEvent::Pull(progress) => {
+                eprint!("\r\x1b[K{}", progress);
+                if progress.is_done() {
+                    eprintln!();
+                }
+            }
+        },
+    );
+    if let Some(e) = write_error {
+        return output_result(e);
+    }
+    let (model, answer) = result?;
+
+    // 回答の末尾に改行がなければ付ける
+    if !answer.ends_with('\n') {
+        if let Err(e) = writeln!(out) {
+            output_result(e)?;
+        }
+    }
+
+    // 標準出力には回答だけを書き出したいので、利用量は台帳への記録だけ行う
+    if let Some(usage) = usage {
+        let record = UsageRecord {
+            timestamp: Local::now(),
+            session: None,
+            cost: usage.cost(&model),
+            model,
+            usage,
+        };
+        if let Err(e) = UsageLedger::open_default().and_then(|ledger| ledger.append(&record)) {
+            eprintln!("warning: failed to record usage: {:#}", e);
+        }
+    }
+    Ok(())
+}
+
+// 質問がモデルのコンテキストウィンドウ（から回答の分を除いたもの）に収まるかを確かめる
+//
+// 対話モードと違って削れる過去のターンがないため、収まらない場合は送信しない。
+fn check_tokens(
+    history: &MessageHistory,
+    model: &Model,
+    params: &GenerationParams,
+    context: &ContextConfig,
+) -> Result<()> {
+    let tokens = model.capabilities().tokenizer.count_history(history);
+    let limit = context.token_limit(model, params);
+    if tokens > limit {
+        return Err(anyhow!(
+            "質問が長すぎるため{}に送信しません（{} tokens、上限は{} tokens）",
+            model.name,
+            tokens,
+            limit
+        ));
+    }
+    Ok(())
+}
+
+// 標準出力への書き込みエラーを、終了時の結果に変換する
+//
+// 読み手が先に終了した場合（`| head` など）は、それ以上書き出す必要がないだけなので正常終了にする。
+// それ以外のエラーは、通信エラーの終了コードにならないようにメッセージだけのエラーにする。
+fn output_result(err: io::Error) -> Result<()> {
+    if err.kind() == io::ErrorKind::BrokenPipe {
+        return Ok(());
+    }
+    Err(anyhow!("標準出力に書き込めませんでした: {}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api_error::ApiErrorKind;
+
+    #[test]
+    fn output_errors_and_exit_codes() {
+        assert!(output_result(io::ErrorKind::BrokenPipe.into()).is_ok());
+
+        let err = output_result(io::Error::other("disk full")).unwrap_err();
+        assert_eq!(ApiErrorKind::classify(&err).exit_code(), 1);
+
+        // 受信中の切断は通信エラーのまま
+        let err: anyhow::Error = io::Error::from(io::ErrorKind::ConnectionReset).into();
+        assert_eq!(ApiErrorKind::classify(&err).exit_code(), 6);
+    }
+
+    #[test]
+    fn reject_prompt_over_context_window() {
+        let model = Model::new("llama3.2", crate::model::Campany::Ollama);
+        let params = GenerationParams::default();
+        let context = ContextConfig::default();
+
+        let mut history = MessageHistory::default();
+        history.push(Role::User, "short question");
+        assert!(check_tokens(&history, &model, &params, &context).is_ok());
+
+        let mut history = MessageHistory::default();
+        history.push(Role::User, &"word ".repeat(5_000));
+        let err = check_tokens(&history, &model, &params, &context).unwrap_err();
+        assert!(err.to_string().contains("長すぎる"));
+    }
+}
@@ -1,5 +1,7 @@
 use serde::Deserialize;
 
+use crate::{model::is_openai_chat_model, usage::Usage};
+
 ///
 /// ChatGPTのmodel一覧を取得するAPIのレスポンス
 ///
@@ -25,31 +27,49 @@ use serde::Deserialize;
 /// }
 /// ```
 ///
+//
+// OpenAI互換のサーバーは `object` や `created` などを返さないことがあるので、省略できるようにしている。
 #[derive(Debug, Deserialize)]
 pub struct Models {
+    #[serde(default)]
     pub object: String,
     pub data: Vec<Model>,
 }
 
 impl Models {
+    /// チャットに使えるモデルを、新しい順に返す
+    ///
+    /// 埋め込みや音声などのチャットに使えないモデルは除く。
     pub fn get_gpts(&self) -> Vec<String> {
-        // gpt-から始まるか、oから始まるか
         let mut filterd: Vec<&Model> = self
             .data
             .iter()
-            .filter(|m| m.id.contains("gpt-") || m.id.contains("o"))
+            .filter(|m| is_openai_chat_model(&m.id))
             .collect();
         filterd.sort_by_key(|m| m.created);
         filterd.reverse();
         filterd.iter().map(|m| m.id.clone()).collect()
     }
+
+    /// 全てのモデルを、新しい順に返す
+    ///
+    /// OpenAI互換のサーバーのモデル名はレジストリで判定できないので、絞り込まない。
+    pub fn get_all(&self) -> Vec<String> {
+        let mut models: Vec<&Model> = self.data.iter().collect();
+        models.sort_by_key(|m| m.created);
+        models.reverse();
+        models.iter().map(|m| m.id.clone()).collect()
+    }
 }
 
 #[derive(Debug, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Model {
     pub id: String,
+    #[serde(default)]
     pub object: String,
+    #[serde(default)]
     pub created: u64,
+    #[serde(default)]
     pub owned_by: String,
 }
 
@@ -68,18 +88,27 @@ pub struct Model {
 //
 // data: [DONE]
 // ```
+//
+// `stream_options.include_usage` を指定した場合は、`[DONE]` の直前に `choices` が空で `usage` を含むチャンクが送られてくる。
 #[derive(Debug, Deserialize)]
 pub struct ChatCompletionStreamChunk {
+    #[serde(default)]
     pub id: String,
+    #[serde(default)]
     pub object: String,
+    #[serde(default)]
     pub created: u64,
+    #[serde(default)]
     pub model: String,
     pub choices: Vec<StreamChoice>,
+    #[serde(default)]
+    pub usage: Option<CompletionUsage>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct StreamChoice {
     pub delta: Delta,
+    #[serde(default)]
     pub index: u64,
     pub finish_reason: Option<String>,
 }
@@ -103,17 +132,25 @@ pub struct Delta {
 // ```
 #[derive(Debug, Deserialize)]
 pub struct ChatCompletionResponse {
+    #[serde(default)]
     pub id: String,
+    #[serde(default)]
     pub object: String,
+    #[serde(default)]
     pub created: u64,
+    #[serde(default)]
     pub model: String,
     pub choices: Vec<Choice>,
+    #[serde(default)]
+    pub usage: Option<CompletionUsage>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Choice {
+    #[serde(default)]
     pub index: u64,
     pub message: Message,
+    #[serde(default)]
     pub finish_reason: String,
 }
 
@@ -122,3 +159,36 @@ pub struct Message {
     pub role: String,
     pub content: String,
 }
+
+// トークン数は下記のような形で返ってくる
+//
+// ```json
+// "usage": {"prompt_tokens": 1200, "completion_tokens": 300, "prompt_tokens_details": {"cached_tokens": 1024}}
+// ```
+#[derive(Debug, Deserialize)]
+pub struct CompletionUsage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    #[serde(default)]
+    pub prompt_tokens_details: Option<PromptTokensDetails>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PromptTokensDetails {
+    #[serde(default)]
+    pub cached_tokens: Option<u64>,
+}
+
+impl From<&CompletionUsage> for Usage {
+    fn from(usage: &CompletionUsage) -> Self {
+        Usage {
+            input_tokens: usage.prompt_tokens,
+            output_tokens: usage.completion_tokens,
+            cached_tokens: usage
+                .prompt_tokens_details
+                .as_ref()
+                .and_then(|d| d.cached_tokens)
+                .unwrap_or(0),
+        }
+    }
+}
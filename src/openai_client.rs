@@ -1,19 +1,36 @@
-use std::io::{stdout, BufRead, BufReader, Write};
-
 use anyhow::Result;
 
 use crate::{
-    chat_input,
+    azure::Azure,
     chat_message::{self, MessageHistory, Role},
-    model::{Campany, Model},
+    http,
+    model::{Campany, Model, SystemRole},
     openai_api_res::{ChatCompletionResponse, ChatCompletionStreamChunk, Models},
+    params::GenerationParams,
+    provider::{ChatProvider, StreamEvent},
 };
-use requestty::Question;
 use reqwest::blocking::Client;
 use serde_json::json;
 
+/// OpenAIのAPIのデフォルトのベースURL
+pub const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
+
+/// OpenAIのChat Completions APIのクライアント
+///
+/// ベースURLを変えると、vLLMやLM StudioなどのOpenAI互換のサーバーにも接続できる。
+/// Azure OpenAIに接続する場合は、URLと認証の方法だけをAzureの形に変える。
 pub struct ChatGPTClient {
+    /// 空の場合は `Authorization` ヘッダーを送らない
     openai_token: String,
+    base_url: String,
+    /// 設定ファイルの `[providers.<name>]` で追加した接続先の名前
+    instance: Option<String>,
+    /// 設定ファイルで指定したモデル一覧。空の場合はAPIから取得する
+    models: Vec<String>,
+    /// Azure OpenAIに接続する場合の設定
+    azure: Option<Azure>,
+    /// ストリームで `stream_options.include_usage` を送るか。`None` の場合は組み込みのOpenAIだけ送る
+    stream_usage: Option<bool>,
     model: Option<Model>,
     client: Client,
 }
@@ -22,166 +39,134 @@ impl ChatGPTClient {
     pub fn new(openai_token: String) -> Self {
         Self {
             openai_token,
+            base_url: DEFAULT_BASE_URL.to_string(),
+            instance: None,
+            models: Vec::new(),
+            azure: None,
+            stream_usage: None,
             model: None,
             client: Client::new(),
         }
     }
 
-    pub fn run_chatgpt(&self, mut messages: MessageHistory) -> Result<()> {
-        // ユーザーからの質問を無限ループで受け付ける
-        loop {
-            // ユーザーからの入力を受け付ける
-            println!("👤 質問を入力してください。（入力完了時は改行してCtrl+D）>");
-            let message = chat_input::stdin_to_string()?;
-
-            // 入力した質問を履歴に追加
-            messages.push(Role::User, &message);
+    /// APIのベースURLを変更する
+    pub fn with_base_url(mut self, base_url: &str) -> Self {
+        self.base_url = base_url.trim_end_matches('/').to_string();
+        self
+    }
 
-            println!("🤖 ChatGPTからの回答 >");
+    /// 設定ファイルで追加した接続先として、組み込みのOpenAIとは別のベンダーとして扱う
+    pub fn with_instance(mut self, name: &str) -> Self {
+        self.instance = Some(name.to_string());
+        self
+    }
 
-            // [TODO] エラー時、exitするのではなく、エラー内容を表示してループを継続したい
-            let assistant_response = self.send_messages(&messages)?;
-            messages.push(Role::Assistant, &assistant_response);
+    /// モデル一覧をAPIから取得せず、指定した一覧を使う
+    pub fn with_models(mut self, models: Vec<String>) -> Self {
+        self.models = models;
+        self
+    }
 
-            // 次の質問との間に空行を入れる
-            println!();
+    /// Azure OpenAIに接続する。モデル一覧はデプロイの対応から作る
+    pub fn with_azure(mut self, azure: Azure) -> Self {
+        if self.models.is_empty() {
+            self.models = azure.models();
         }
+        self.azure = Some(azure);
+        self
+    }
+
+    /// ストリームの最後にトークン数を受け取るための `stream_options` を送るかを指定する
+    ///
+    /// OpenAI互換のサーバーには `stream_options` を受け付けないものがあるため、
+    /// 指定しない場合は組み込みのOpenAIの接続先だけで送る。
+    pub fn with_stream_usage(mut self, enabled: Option<bool>) -> Self {
+        self.stream_usage = enabled;
+        self
+    }
+
+    fn sends_stream_usage(&self) -> bool {
+        self.stream_usage
+            .unwrap_or(self.instance.is_none() && self.azure.is_none())
     }
 
     pub fn fetch_models(&self) -> Result<Vec<Model>> {
-        let url = "https://api.openai.com/v1/models";
-        let headers = self.generate_headers()?;
-        let response = self.get_request(url, headers)?;
-        let models: Models = response.json()?;
-        let gpts: Vec<String> = models.get_gpts();
-        let gpts: Vec<String> = gpts
+        let names = if !self.models.is_empty() || self.azure.is_some() {
+            self.models.clone()
+        } else {
+            let url = format!("{}/models", self.base_url);
+            let headers = self.generate_headers()?;
+            let response = http::get_request(&self.client, &url, headers)?;
+            let models: Models = response.json()?;
+            // OpenAI互換のサーバーでは、チャットに使えるモデルかどうかを判定できない
+            match self.instance {
+                Some(_) => models.get_all(),
+                None => models.get_gpts(),
+            }
+        };
+
+        let models: Vec<Model> = names
             .iter()
             .filter(|m| !m.starts_with("ft:"))
-            .map(|m| m.to_string())
-            .collect();
-
-        let models: Vec<Model> = gpts
-            .into_iter()
-            .map(|m| Model {
-                name: m,
-                campany: crate::model::Campany::OpenAI,
+            .map(|m| match &self.instance {
+                Some(instance) => Model::with_provider(m, Campany::OpenAI, instance),
+                None => Model::new(m, Campany::OpenAI),
             })
             .collect();
 
         Ok(models)
     }
 
-    pub fn select_model(&mut self) -> Result<()> {
-        let url = "https://api.openai.com/v1/models";
-        let headers = self.generate_headers()?;
-        let response = self.get_request(url, headers)?;
-
-        let models: Models = response.json()?;
-        let gpts = models.get_gpts();
-
-        let select = Question::select("theme")
-            .should_loop(false)
-            .message("🤖 ChatGPTのモデルを選択してください (Ctrl+c to exit)")
-            .choices(gpts)
-            .default(0)
-            .build();
-
-        let answer = requestty::prompt_one(select)?;
-        let model = &answer.as_list_item().unwrap().text;
-        self.model = Some(Model {
-            name: model.to_owned(),
-            campany: Campany::OpenAI,
-        });
-
-        Ok(())
-    }
-
-    pub fn set_model(&mut self, model: Model) {
-        self.model = Some(model);
-    }
-
-    pub fn send_messages(&self, message_history: &chat_message::MessageHistory) -> Result<String> {
-        let url = "https://api.openai.com/v1/chat/completions";
-        let headers = self.generate_headers()?;
-        let body = self.generate_body_from_history(message_history);
-        let response = self.send_post_request(url, headers, body)?;
-
-        // もしモデルの名前が「o」から始まる場合は、ストリームに対応していないので、非ストリームで処理する
-        if self.model.as_ref().unwrap().name.starts_with("o") {
-            let content = self.print_chat_no_stream(response)?;
-            println!();
-            Ok(content)
-        } else {
-            // ストリームの結果を連結して返す
-            let joined_content = self.print_chat_stream(response)?;
-            println!();
-            Ok(joined_content)
-        }
-    }
-
     // APIへ送信するbodyを作成する。
     // メッセージ履歴は全て連結して送る必要がある。
     fn generate_body_from_history(
         &self,
         message_history: &chat_message::MessageHistory,
+        params: &GenerationParams,
     ) -> serde_json::Value {
-        let messages = message_history
-            .messages
-            .iter()
-            .map(|m| json!({"role": m.role.to_string(), "content": m.content}))
-            .collect::<Vec<_>>();
-
-        let model_name = self.model.as_ref().unwrap().name.clone();
+        let model = self.model.as_ref().unwrap();
+        let capabilities = model.capabilities();
+        let model_name = model.name.clone();
+        let messages = openai_messages(message_history, capabilities.system_role);
 
         let mut json = json!({
-            "top_p": 0.5,
             "stream": true,
             "model": model_name,
             "messages": messages,
         });
 
-        // o1やo1-miniなどはtop_pとstreamに対応していないので、削除
-        if model_name.starts_with("o") {
-            json.as_object_mut().unwrap().remove("top_p");
-            json.as_object_mut().unwrap().remove("stream");
+        if let Some(temperature) = params.temperature {
+            json["temperature"] = json!(temperature);
         }
-
-        json
-    }
-
-    fn get_request(
-        &self,
-        url: &str,
-        headers: reqwest::header::HeaderMap,
-    ) -> Result<reqwest::blocking::Response> {
-        let res = self.client.get(url).headers(headers).send()?;
-
-        if res.status().is_success() {
-            Ok(res)
-        } else {
-            Err(anyhow::anyhow!(
-                "failed to send GET request: {}",
-                res.text()?
-            ))
+        if let Some(top_p) = params.top_p {
+            json["top_p"] = json!(top_p);
+        }
+        if let Some(max_tokens) = params.max_tokens {
+            // 推論モデルはmax_tokensの代わりにmax_completion_tokensを使う
+            if capabilities.reasoning {
+                json["max_completion_tokens"] = json!(max_tokens);
+            } else {
+                json["max_tokens"] = json!(max_tokens);
+            }
+        }
+        if !params.stop.is_empty() {
+            json["stop"] = json!(params.stop);
+        }
+        if let Some(seed) = params.seed {
+            json["seed"] = json!(seed);
         }
-    }
 
-    fn send_post_request(
-        &self,
-        url: &str,
-        headers: reqwest::header::HeaderMap,
-        body: serde_json::Value,
-    ) -> Result<reqwest::blocking::Response> {
-        let res = self.client.post(url).headers(headers).json(&body).send()?;
-
-        if res.status().is_success() {
-            Ok(res)
+        // o1やo1-miniなどはstreamに対応していないので、削除
+        // ストリームの場合は、最後にトークン数を受け取るためにinclude_usageを指定する
+        if capabilities.streaming {
+            if self.sends_stream_usage() {
+                json["stream_options"] = json!({"include_usage": true});
+            }
         } else {
-            Err(anyhow::anyhow!(
-                "failed to send POST request: {}",
-                res.text()?
-            ))
+            json.as_object_mut().unwrap().remove("stream");
         }
+
+        json
     }
 
     // APIを呼び出すのに必要なヘッダーを生成する
@@ -191,16 +176,23 @@ impl ChatGPTClient {
             "Content-Type",
             reqwest::header::HeaderValue::from_static("application/json"),
         );
-        headers.insert(
-            "Authorization",
-            reqwest::header::HeaderValue::from_str(&format!("Bearer {}", self.openai_token))?,
-        );
+        if let Some(azure) = &self.azure {
+            azure.insert_auth_headers(&mut headers)?;
+            return Ok(headers);
+        }
+        // ローカルのサーバーなど、APIキーが不要な接続先もある
+        if !self.openai_token.is_empty() {
+            headers.insert(
+                "Authorization",
+                reqwest::header::HeaderValue::from_str(&format!("Bearer {}", self.openai_token))?,
+            );
+        }
         Ok(headers)
     }
 
-    // print_chat_stream
+    // read_chat_stream
     //
-    // APIから下記のようなデータが連続して送られてくるので、`choices[0].delta.content`を取得して逐次Printする。
+    // APIから下記のようなデータが連続して送られてくるので、`choices[0].delta.content`を取得して逐次通知する。
     //
     // 以下のようなデータが送られてくる:
     // ```
@@ -220,20 +212,15 @@ impl ChatGPTClient {
     // `data: [DONE]` が送られてきたら読み込みを終了し、ループを抜ける。
     //
     // 送られてきた `choices[0].delta.content` は `joined_string`に連結し、最後に返す。
-    fn print_chat_stream(&self, response: reqwest::blocking::Response) -> Result<String> {
+    fn read_chat_stream(
+        &self,
+        response: reqwest::blocking::Response,
+        on_event: &mut dyn FnMut(StreamEvent),
+    ) -> Result<String> {
         let mut joined_string = String::new();
 
-        // レスポンスを読み込むためのリーダーを作成する
-        let reader = BufReader::new(response);
-
-        // APIからの回答が横に長い場合は、読みづらいので改行する。
-        // ただ、適切な改行を行うためにはTerminalの幅を取得する必要があるため、
-        // 現状はMAXを仮で設定し、実質的に途中の強制改行が発生しないようにしている。
-        let mut line_length = 0;
-        let max_line_length = usize::MAX;
-
         // レスポンスの各行を処理する
-        for line in reader.lines() {
+        for line in http::stream_lines(response) {
             let line = line?;
 
             // ループを抜けるための条件
@@ -245,33 +232,16 @@ impl ChatGPTClient {
             if let Some(data) = line.strip_prefix("data: ") {
                 let chunk: ChatCompletionStreamChunk = serde_json::from_str(data.trim())?;
 
+                if let Some(usage) = &chunk.usage {
+                    on_event(StreamEvent::Usage(usage.into()));
+                }
+
                 // 選択肢の各要素を処理する
                 for choice in chunk.choices {
                     if let Some(content) = choice.delta.content {
-                        // 逐次Printする
-                        print!("{}", content);
-
                         // 逐次連結する
                         joined_string.push_str(&content);
-
-                        // 改行コードが含まれている場合は文字数をリセットする
-                        if content.contains('\n') {
-                            line_length = 0;
-                        }
-
-                        // 文字数をプラスする。
-                        // UTF-8文字としてカウントしたいので、chars().count()を使う。
-                        // https://doc.rust-lang.org/std/string/struct.String.html#utf-8
-                        line_length += content.chars().count();
-
-                        // 100文字に達した場合は改行する
-                        if line_length >= max_line_length {
-                            println!();
-                            line_length = 0;
-                        }
-
-                        // 逐次Printしたものを即座に表示する
-                        stdout().flush().unwrap();
+                        on_event(StreamEvent::Text(content));
                     }
                 }
             }
@@ -280,29 +250,159 @@ impl ChatGPTClient {
         Ok(joined_string)
     }
 
-    fn print_chat_no_stream(&self, response: reqwest::blocking::Response) -> Result<String> {
+    fn read_chat_no_stream(
+        &self,
+        response: reqwest::blocking::Response,
+        on_event: &mut dyn FnMut(StreamEvent),
+    ) -> Result<String> {
         let mut content = String::new();
         let response: ChatCompletionResponse = response.json()?;
         for choice in response.choices {
             content.push_str(&choice.message.content);
         }
-        println!("{}", content);
+        on_event(StreamEvent::Text(content.clone()));
+        if let Some(usage) = &response.usage {
+            on_event(StreamEvent::Usage(usage.into()));
+        }
         Ok(content)
     }
 }
 
+// メッセージ履歴をOpenAIのAPIが受け付ける形に変換する
+//
+// システムプロンプトは、モデルに応じて`system`または`developer`ロールで送る。
+// システムプロンプトに対応していないモデルでは、システムプロンプトを最初の質問の前に連結する。
+pub(crate) fn openai_messages(
+    message_history: &MessageHistory,
+    system_role: SystemRole,
+) -> Vec<serde_json::Value> {
+    let mut messages = Vec::new();
+
+    let mut pending_system = None;
+    if let Some(system) = message_history.system() {
+        match system_role {
+            SystemRole::Developer => messages.push(json!({"role": "developer", "content": system})),
+            SystemRole::Unsupported => pending_system = Some(system),
+            SystemRole::System | SystemRole::TopLevel => {
+                messages.push(json!({"role": "system", "content": system}))
+            }
+        }
+    }
+
+    for m in message_history.conversation() {
+        let content = match pending_system {
+            Some(system) if m.role == Role::User => {
+                pending_system = None;
+                format!("{}\n\n{}", system, m.content)
+            }
+            _ => m.content.clone(),
+        };
+        messages.push(json!({"role": m.role.to_string(), "content": content}));
+    }
+
+    messages
+}
+
+impl ChatProvider for ChatGPTClient {
+    fn campany(&self) -> Campany {
+        Campany::OpenAI
+    }
+
+    fn instance(&self) -> Option<&str> {
+        self.instance.as_deref()
+    }
+
+    fn list_models(&self) -> Result<Vec<Model>> {
+        self.fetch_models()
+    }
+
+    fn set_model(&mut self, model: Model) {
+        self.model = Some(model);
+    }
+
+    fn model(&self) -> Option<&Model> {
+        self.model.as_ref()
+    }
+
+    fn send_messages(
+        &self,
+        message_history: &MessageHistory,
+        params: &GenerationParams,
+        on_event: &mut dyn FnMut(StreamEvent),
+    ) -> Result<String> {
+        let url = match &self.azure {
+            Some(azure) => azure.chat_url(&self.base_url, &self.model.as_ref().unwrap().name),
+            None => format!("{}/chat/completions", self.base_url),
+        };
+        let headers = self.generate_headers()?;
+        let body = self.generate_body_from_history(message_history, params);
+        let response = http::send_post_request(&self.client, &url, headers, body, &mut |r| {
+            on_event(StreamEvent::Retry(r.clone()))
+        })?;
+
+        // ストリームに対応していないモデルは、非ストリームで処理する
+        if !self.model.as_ref().unwrap().capabilities().streaming {
+            self.read_chat_no_stream(response, on_event)
+        } else {
+            // ストリームの結果を連結して返す
+            self.read_chat_stream(response, on_event)
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use std::env;
-
     use super::*;
 
     #[test]
-    fn select_model() {
-        let openai_token = env::var("OPENAI_API_KEY").expect("OPENAI_API_KEY must be set");
-        let mut client = ChatGPTClient::new(openai_token);
+    fn system_prompt_role_depends_on_model() {
+        let mut history = MessageHistory::default();
+        history.push(Role::User, "hello");
+        history.set_system("Be concise.");
 
-        let result = client.select_model();
-        assert!(result.is_ok());
+        let role = |name: &str| Model::from_name(name).capabilities().system_role;
+
+        let messages = openai_messages(&history, role("gpt-4o"));
+        assert_eq!(messages[0]["role"], "system");
+        assert_eq!(messages[1]["content"], "hello");
+
+        let messages = openai_messages(&history, role("o3-mini"));
+        assert_eq!(messages[0]["role"], "developer");
+
+        let messages = openai_messages(&history, role("o1-mini"));
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0]["content"], "Be concise.\n\nhello");
+    }
+
+    #[test]
+    fn stream_options_only_for_builtin_openai() {
+        let mut history = MessageHistory::default();
+        history.push(Role::User, "hello");
+        let params = GenerationParams::default();
+
+        let mut client = ChatGPTClient::new(String::new());
+        client.set_model(Model::from_name("gpt-4o"));
+        let body = client.generate_body_from_history(&history, &params);
+        assert_eq!(body["stream_options"]["include_usage"], true);
+
+        let mut client = ChatGPTClient::new(String::new())
+            .with_base_url("http://localhost:8000/v1")
+            .with_instance("local");
+        client.set_model(Model::with_provider(
+            "qwen2.5-coder",
+            Campany::OpenAI,
+            "local",
+        ));
+        let body = client.generate_body_from_history(&history, &params);
+        assert!(body.get("stream_options").is_none());
+
+        let mut client = client.with_stream_usage(Some(true));
+        client.set_model(Model::with_provider(
+            "qwen2.5-coder",
+            Campany::OpenAI,
+            "local",
+        ));
+        let body = client.generate_body_from_history(&history, &params);
+        assert_eq!(body["stream_options"]["include_usage"], true);
     }
 }
@@ -0,0 +1,231 @@
+use std::fmt;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::model::{Campany, Model};
+
+/// 回答の生成を制御するパラメータ
+///
+/// 未設定の項目はAPIへ送信せず、各ベンダーのデフォルト値に任せる。
+/// ベンダーごとのbodyへの変換は各クライアントで行う。
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GenerationParams {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    /// コンテキストウィンドウのトークン数（Ollamaのみ）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub num_ctx: Option<u32>,
+    /// 回答後にモデルをメモリに残す時間。`5m` のような時間か秒数（Ollamaのみ）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_alive: Option<String>,
+}
+
+/// `/set` で設定できるパラメータ名
+pub const PARAM_NAMES: &[&str] = &[
+    "temperature",
+    "max_tokens",
+    "top_p",
+    "stop",
+    "seed",
+    "num_ctx",
+    "keep_alive",
+];
+
+impl GenerationParams {
+    /// `other` で設定されている項目で上書きする
+    pub fn merge(&mut self, other: &GenerationParams) {
+        if other.temperature.is_some() {
+            self.temperature = other.temperature;
+        }
+        if other.max_tokens.is_some() {
+            self.max_tokens = other.max_tokens;
+        }
+        if other.top_p.is_some() {
+            self.top_p = other.top_p;
+        }
+        if !other.stop.is_empty() {
+            self.stop = other.stop.clone();
+        }
+        if other.seed.is_some() {
+            self.seed = other.seed;
+        }
+        if other.num_ctx.is_some() {
+            self.num_ctx = other.num_ctx;
+        }
+        if other.keep_alive.is_some() {
+            self.keep_alive = other.keep_alive.clone();
+        }
+    }
+
+    /// 名前を指定してパラメータを設定する
+    ///
+    /// 値に `default` を指定すると未設定に戻す。`stop` はカンマ区切りで複数指定できる。
+    pub fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        let value = value.trim();
+        let reset = value == "default";
+        match name {
+            "temperature" => self.temperature = parse_option(value, reset)?,
+            "max_tokens" => self.max_tokens = parse_option(value, reset)?,
+            "top_p" => self.top_p = parse_option(value, reset)?,
+            "seed" => self.seed = parse_option(value, reset)?,
+            "num_ctx" => self.num_ctx = parse_option(value, reset)?,
+            "keep_alive" => self.keep_alive = parse_option(value, reset)?,
+            "stop" => {
+                self.stop = if reset {
+                    Vec::new()
+                } else {
+                    value
+                        .split(',')
+                        .map(|s| s.trim().to_owned())
+                        .filter(|s| !s.is_empty())
+                        .collect()
+                }
+            }
+            _ => {
+                return Err(anyhow!(
+                    "unknown parameter: {} (利用できるパラメータ: {})",
+                    name,
+                    PARAM_NAMES.join(", ")
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// モデルがこのパラメータに対応しているかを確認する
+    pub fn validate(&self, model: &Model) -> Result<()> {
+        let capabilities = model.capabilities();
+
+        if let Some(temperature) = self.temperature {
+            if !capabilities.temperature {
+                return Err(anyhow!("{}はtemperatureに対応していません", model.name));
+            }
+            if !(0.0..=capabilities.max_temperature).contains(&temperature) {
+                return Err(anyhow!(
+                    "{}のtemperatureは0から{}の範囲で指定してください",
+                    model.name,
+                    capabilities.max_temperature
+                ));
+            }
+        }
+        if let Some(top_p) = self.top_p {
+            if !capabilities.top_p {
+                return Err(anyhow!("{}はtop_pに対応していません", model.name));
+            }
+            if !(0.0..=1.0).contains(&top_p) {
+                return Err(anyhow!("top_pは0から1の範囲で指定してください"));
+            }
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            if max_tokens == 0 {
+                return Err(anyhow!("max_tokensは1以上を指定してください"));
+            }
+            if let Some(limit) = capabilities.max_output_tokens {
+                if max_tokens > limit {
+                    return Err(anyhow!(
+                        "{}のmax_tokensは{}以下で指定してください",
+                        model.name,
+                        limit
+                    ));
+                }
+            }
+        }
+        if !self.stop.is_empty() {
+            if !capabilities.stop {
+                return Err(anyhow!("{}はstopに対応していません", model.name));
+            }
+            // OpenAIはstopを4つまでしか受け付けない
+            if model.campany == Campany::OpenAI && self.stop.len() > 4 {
+                return Err(anyhow!("stopは4つまで指定できます"));
+            }
+            // Geminiは5つまで
+            if model.campany == Campany::Gemini && self.stop.len() > 5 {
+                return Err(anyhow!("stopは5つまで指定できます"));
+            }
+        }
+        if self.seed.is_some() && !capabilities.seed {
+            return Err(anyhow!("{}はseedに対応していません", model.name));
+        }
+        if model.campany != Campany::Ollama {
+            if self.num_ctx.is_some() {
+                return Err(anyhow!("{}はnum_ctxに対応していません", model.name));
+            }
+            if self.keep_alive.is_some() {
+                return Err(anyhow!("{}はkeep_aliveに対応していません", model.name));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_option<T: std::str::FromStr>(value: &str, reset: bool) -> Result<Option<T>> {
+    if reset {
+        return Ok(None);
+    }
+    value
+        .parse()
+        .map(Some)
+        .map_err(|_| anyhow!("invalid value: {}", value))
+}
+
+impl fmt::Display for GenerationParams {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn show<T: fmt::Display>(value: &Option<T>) -> String {
+            value
+                .as_ref()
+                .map(|v| v.to_string())
+                .unwrap_or_else(|| "default".to_string())
+        }
+        writeln!(f, "temperature: {}", show(&self.temperature))?;
+        writeln!(f, "max_tokens:  {}", show(&self.max_tokens))?;
+        writeln!(f, "top_p:       {}", show(&self.top_p))?;
+        if self.stop.is_empty() {
+            writeln!(f, "stop:        default")?;
+        } else {
+            writeln!(f, "stop:        {}", self.stop.join(", "))?;
+        }
+        write!(f, "seed:        {}", show(&self.seed))?;
+        // Ollamaのパラメータは、設定されている場合だけ表示する
+        if let Some(num_ctx) = self.num_ctx {
+            write!(f, "\nnum_ctx:     {}", num_ctx)?;
+        }
+        if let Some(keep_alive) = &self.keep_alive {
+            write!(f, "\nkeep_alive:  {}", keep_alive)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_and_validate_params() {
+        let mut params = GenerationParams::default();
+        params.set("temperature", "1.5").unwrap();
+        params.set("stop", "END, ###").unwrap();
+        assert_eq!(params.temperature, Some(1.5));
+        assert_eq!(params.stop, vec!["END", "###"]);
+        assert!(params.set("temperature", "hot").is_err());
+        assert!(params.set("unknown", "1").is_err());
+
+        let gpt = Model::new("gpt-4o", Campany::OpenAI);
+        let claude = Model::new("claude-3-5-sonnet-20240620", Campany::Claude);
+        assert!(params.validate(&gpt).is_ok());
+        assert!(params.validate(&claude).is_err());
+
+        params.set("temperature", "default").unwrap();
+        params.set("seed", "42").unwrap();
+        assert!(params.validate(&claude).is_err());
+    }
+}
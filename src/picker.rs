@@ -0,0 +1,423 @@
+use std::io::{self, Write};
+
+use anyhow::{anyhow, Result};
+use requestty::Question;
+use requestty_ui::{
+    backend::Backend,
+    events::{KeyCode, KeyEvent},
+    layout::Layout,
+    style::{Color, Stylize},
+    widgets::{self, List, Select, Text},
+    Validation, Widget,
+};
+
+use crate::{
+    model::{Campany, Model},
+    preferences::ModelPreferences,
+    provider::ChatProvider,
+    session::{Session, SessionStore},
+};
+
+/// 全てのProviderからモデル一覧を集め、ユーザーにモデルを選択させる
+///
+/// モデル一覧を取得できないProvider（停止しているOllamaなど）は、警告を表示して読み飛ばす。
+pub fn select_model_input(providers: &[Box<dyn ChatProvider>]) -> Result<Model> {
+    let mut models: Vec<Model> = Vec::new();
+    for provider in providers {
+        match provider.list_models() {
+            Ok(list) => models.extend(list),
+            Err(e) => {
+                let vendor = provider
+                    .instance()
+                    .map_or_else(|| provider.campany().to_string(), str::to_string);
+                eprintln!("⚠️ {}のモデル一覧を取得できませんでした: {}", vendor, e);
+            }
+        }
+    }
+    select_model_from(models)
+}
+
+/// 与えられたモデル一覧からユーザーにモデルを選択させる
+///
+/// モデルはベンダーごとにまとめ、お気に入りは先頭に表示する。
+/// 一覧を表示したまま文字を入力すると、あいまい一致するモデルだけに絞り込む。
+pub fn select_model_from(models: Vec<Model>) -> Result<Model> {
+    if models.is_empty() {
+        return Err(anyhow!("利用できるモデルがありません"));
+    }
+
+    let message = "🤖 利用するモデルを選択してください".to_string();
+    let prompt = ModelPrompt::new(&message, models, ModelPreferences::load());
+
+    let mut backend = requestty_ui::backend::get_backend(io::stdout());
+    let mut events = requestty_ui::events::get_events();
+    let model = requestty_ui::Input::new(prompt, &mut backend).run(&mut events)?;
+
+    widgets::Prompt::write_finished_message(&message.as_str(), &mut backend)?;
+    backend.write_styled(&model.name.as_str().cyan())?;
+    backend.write_all(b"\n")?;
+    backend.flush()?;
+    Ok(model)
+}
+
+// モデル選択の画面の各行
+enum Row {
+    Separator(Text<String>),
+    Model(Model, Text<String>),
+}
+
+// 絞り込んだ結果のモデル一覧
+struct ModelList {
+    rows: Vec<Row>,
+}
+
+impl List for ModelList {
+    fn render_item<B: Backend>(
+        &mut self,
+        index: usize,
+        hovered: bool,
+        mut layout: Layout,
+        b: &mut B,
+    ) -> io::Result<()> {
+        if hovered {
+            b.set_fg(Color::Cyan)?;
+            write!(b, "{} ", requestty_ui::symbols::ARROW)?;
+        } else {
+            b.write_all(b"  ")?;
+            if !self.is_selectable(index) {
+                b.set_fg(Color::DarkGrey)?;
+            }
+        }
+
+        layout.offset_x += 2;
+        match &mut self.rows[index] {
+            Row::Separator(text) | Row::Model(_, text) => text.render(&mut layout, b)?,
+        }
+        b.set_fg(Color::Reset)
+    }
+
+    fn is_selectable(&self, index: usize) -> bool {
+        matches!(self.rows[index], Row::Model(..))
+    }
+
+    fn page_size(&self) -> usize {
+        15
+    }
+
+    fn should_loop(&self) -> bool {
+        false
+    }
+
+    fn height_at(&mut self, index: usize, mut layout: Layout) -> u16 {
+        layout.offset_x += 2;
+        match &mut self.rows[index] {
+            Row::Separator(text) | Row::Model(_, text) => text.height(&mut layout),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.rows.len()
+    }
+}
+
+// キーワードの入力欄とモデル一覧を並べて表示し、入力のたびに一覧を絞り込む
+struct ModelPrompt<'a> {
+    prompt: widgets::Prompt<&'a str>,
+    query: widgets::StringInput,
+    models: Vec<Model>,
+    preferences: ModelPreferences,
+    // 一致するモデルがない場合はNone
+    select: Option<Select<ModelList>>,
+}
+
+impl<'a> ModelPrompt<'a> {
+    fn new(message: &'a str, models: Vec<Model>, preferences: ModelPreferences) -> Self {
+        let mut prompt = Self {
+            prompt: widgets::Prompt::new(message).with_hint("入力で絞り込み, Ctrl+c to exit"),
+            query: widgets::StringInput::new(),
+            models,
+            preferences,
+            select: None,
+        };
+        prompt.refresh();
+        prompt
+    }
+
+    // 入力中のキーワードに合わせて一覧を作り直す
+    fn refresh(&mut self) {
+        let (rows, default) = build_rows(&self.models, self.query.value(), &self.preferences);
+        self.select = default.map(|at| {
+            let mut select = Select::new(ModelList { rows });
+            select.set_at(at);
+            select
+        });
+    }
+}
+
+impl Widget for ModelPrompt<'_> {
+    fn render<B: Backend>(&mut self, layout: &mut Layout, b: &mut B) -> io::Result<()> {
+        self.prompt.render(layout, b)?;
+        self.query.render(layout, b)?;
+        match &mut self.select {
+            Some(select) => select.render(layout, b),
+            None => {
+                layout.line_offset = 0;
+                layout.offset_y += 1;
+                b.move_cursor_to(layout.offset_x, layout.offset_y)?;
+                b.set_fg(Color::DarkGrey)?;
+                write!(b, "  一致するモデルがありません")?;
+                b.set_fg(Color::Reset)?;
+                layout.offset_y += 1;
+                b.move_cursor_to(layout.offset_x, layout.offset_y)
+            }
+        }
+    }
+
+    fn height(&mut self, layout: &mut Layout) -> u16 {
+        let height = self.prompt.height(layout) - 1 + self.query.height(layout);
+        match &mut self.select {
+            Some(select) => height + select.height(layout) - 1,
+            None => height + 1,
+        }
+    }
+
+    fn cursor_pos(&mut self, layout: Layout) -> (u16, u16) {
+        self.query
+            .cursor_pos(layout.with_cursor_pos(self.prompt.cursor_pos(layout)))
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) -> bool {
+        // 上下の移動は一覧に、それ以外はキーワードの入力欄に渡す
+        if matches!(
+            key.code,
+            KeyCode::Up | KeyCode::Down | KeyCode::PageUp | KeyCode::PageDown
+        ) {
+            return self
+                .select
+                .as_mut()
+                .is_some_and(|select| select.handle_key(key));
+        }
+        if self.query.handle_key(key) {
+            self.refresh();
+            return true;
+        }
+        false
+    }
+}
+
+impl requestty_ui::Prompt for ModelPrompt<'_> {
+    type ValidateErr = &'static str;
+    type Output = Model;
+
+    fn validate(&mut self) -> Result<Validation, Self::ValidateErr> {
+        if self.select.is_none() {
+            return Err("一致するモデルがありません");
+        }
+        Ok(Validation::Finish)
+    }
+
+    fn finish(self) -> Self::Output {
+        let select = self.select.expect("validateで確認済み");
+        let at = select.get_at();
+        match select.into_inner().rows.swap_remove(at) {
+            Row::Model(model, _) => model,
+            Row::Separator(_) => unreachable!("区切り行は選択できない"),
+        }
+    }
+}
+
+// 一覧の各行と、最初にカーソルを合わせる行を返す。一致するモデルがなければNone
+//
+// キーワードが空の場合は、お気に入りとベンダーごとにまとめ、前回使ったモデルにカーソルを合わせる。
+// キーワードがある場合は、一致度の高い順に並べる。
+fn build_rows(
+    models: &[Model],
+    query: &str,
+    preferences: &ModelPreferences,
+) -> (Vec<Row>, Option<usize>) {
+    let query = query.trim();
+    let label =
+        |model: &Model| Row::Model(model.clone(), Text::new(model_label(model, preferences)));
+
+    if !query.is_empty() {
+        let rows: Vec<Row> = filter_models(models, query).iter().map(label).collect();
+        let default = (!rows.is_empty()).then_some(0);
+        return (rows, default);
+    }
+
+    let favorites: Vec<&Model> = models
+        .iter()
+        .filter(|m| preferences.is_favorite(m))
+        .collect();
+    // お気に入りは先頭にまとめたので、ベンダーごとのまとまりには含めない
+    let others = || models.iter().filter(|m| !preferences.is_favorite(m));
+
+    let mut groups: Vec<(String, Vec<&Model>)> = Vec::new();
+    if !favorites.is_empty() {
+        groups.push(("⭐ お気に入り".to_string(), favorites));
+    }
+    for campany in Campany::ALL {
+        let group: Vec<&Model> = others()
+            .filter(|m| m.campany == campany && m.provider.is_none())
+            .collect();
+        if !group.is_empty() {
+            groups.push((campany.to_string(), group));
+        }
+    }
+    // 設定ファイルで追加した接続先は、それぞれ別のベンダーとしてまとめる
+    let mut instances: Vec<&str> = Vec::new();
+    for provider in others().filter_map(|m| m.provider.as_deref()) {
+        if !instances.contains(&provider) {
+            instances.push(provider);
+        }
+    }
+    for instance in instances {
+        let group: Vec<&Model> = others()
+            .filter(|m| m.provider.as_deref() == Some(instance))
+            .collect();
+        groups.push((instance.to_string(), group));
+    }
+
+    let mut rows = Vec::new();
+    let mut default = None;
+    for (title, group) in groups {
+        rows.push(Row::Separator(Text::new(format!("── {} ──", title))));
+        for model in group {
+            if default.is_none() && preferences.is_last_used(model) {
+                default = Some(rows.len());
+            }
+            rows.push(label(model));
+        }
+    }
+    // 前回使ったモデルがなければ、最初のモデルにカーソルを合わせる
+    let first_model = rows.iter().position(|r| matches!(r, Row::Model(..)));
+    (rows, default.or(first_model))
+}
+
+// モデル選択の画面に表示する行
+fn model_label(model: &Model, preferences: &ModelPreferences) -> String {
+    let mut label = format!("{:<34} {}", model.name, model.capabilities().summary());
+    if preferences.is_last_used(model) {
+        label.push_str("  (前回)");
+    }
+    label
+}
+
+// キーワードにあいまい一致するモデルを、一致度の高い順に返す
+fn filter_models(models: &[Model], query: &str) -> Vec<Model> {
+    if query.is_empty() {
+        return models.to_vec();
+    }
+    let mut scored: Vec<(i32, &Model)> = models
+        .iter()
+        .filter_map(|m| fuzzy_score(query, &m.name).map(|score| (score, m)))
+        .collect();
+    // 一致度が同じ場合は元の並び順を保つ
+    scored.sort_by_key(|(score, _)| -score);
+    scored.into_iter().map(|(_, m)| m.clone()).collect()
+}
+
+/// キーワードの文字が順番通りに含まれていれば、一致度を返す
+///
+/// 連続して一致した文字や、単語の先頭で一致した文字ほど一致度が高くなる。
+/// 大文字と小文字は区別しない。
+pub fn fuzzy_score(query: &str, candidate: &str) -> Option<i32> {
+    let candidate: Vec<char> = candidate.to_lowercase().chars().collect();
+    let mut score = 0;
+    let mut position = 0;
+    let mut previous_match: Option<usize> = None;
+
+    for q in query.to_lowercase().chars().filter(|c| !c.is_whitespace()) {
+        let found = candidate[position..].iter().position(|&c| c == q)? + position;
+        score += 1;
+        if previous_match.is_some_and(|p| p + 1 == found) {
+            score += 5;
+        }
+        if found == 0 || !candidate[found - 1].is_alphanumeric() {
+            score += 3;
+        }
+        previous_match = Some(found);
+        position = found + 1;
+    }
+
+    // 短い名前ほど優先する
+    Some(score * 100 - candidate.len() as i32)
+}
+
+/// 保存済みのセッションからユーザーに再開するものを選択させる
+pub fn select_session_input(store: &SessionStore) -> Result<Session> {
+    let sessions = store.list()?;
+    if sessions.is_empty() {
+        return Err(anyhow!("保存されたセッションがありません"));
+    }
+
+    let select = Question::select("session")
+        .should_loop(false)
+        .message("💾 再開するセッションを選択してください (Ctrl+c to exit)")
+        .choices(sessions.iter().map(|s| s.summary()))
+        .default(0)
+        .build();
+    let answer = requestty::prompt_one(select)?;
+    let session_index = answer.as_list_item().unwrap().index;
+    Ok(sessions[session_index].clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fuzzy_match_models() {
+        assert!(fuzzy_score("4om", "gpt-4o-mini").is_some());
+        assert!(fuzzy_score("sonnet", "claude-3-haiku-20240307").is_none());
+        assert!(
+            fuzzy_score("gpt4", "gpt-4o").unwrap()
+                > fuzzy_score("gpt4", "gpt-3.5-turbo-0314").unwrap()
+        );
+
+        let models = vec![
+            Model::from_name("gpt-4o-mini"),
+            Model::from_name("gpt-4o"),
+            Model::from_name("claude-3-haiku-20240307"),
+        ];
+        let filtered = filter_models(&models, "4o");
+        assert_eq!(filtered.len(), 2);
+        assert_eq!(filtered[0].name, "gpt-4o");
+    }
+
+    #[test]
+    fn favorites_listed_once() {
+        let models = vec![
+            Model::from_name("gpt-4o-mini"),
+            Model::from_name("gpt-4o"),
+            Model::from_name("claude-3-haiku-20240307"),
+        ];
+        let preferences = ModelPreferences {
+            last_used: Some(Model::from_name("gpt-4o")),
+            favorites: vec![Model::from_name("claude-3-haiku-20240307")],
+        };
+        let names = |rows: &[Row]| -> Vec<String> {
+            rows.iter()
+                .filter_map(|r| match r {
+                    Row::Model(m, _) => Some(m.name.clone()),
+                    Row::Separator(_) => None,
+                })
+                .collect()
+        };
+
+        let (rows, default) = build_rows(&models, "", &preferences);
+        assert_eq!(
+            names(&rows),
+            ["claude-3-haiku-20240307", "gpt-4o-mini", "gpt-4o"]
+        );
+        assert!(matches!(&rows[default.unwrap()], Row::Model(m, _) if m.name == "gpt-4o"));
+
+        let (rows, default) = build_rows(&models, "4o", &preferences);
+        assert_eq!(names(&rows), ["gpt-4o", "gpt-4o-mini"]);
+        assert_eq!(default, Some(0));
+
+        let (rows, default) = build_rows(&models, "xyz", &preferences);
+        assert!(rows.is_empty());
+        assert_eq!(default, None);
+    }
+}
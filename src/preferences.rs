@@ -0,0 +1,75 @@
+use std::{fs, path::PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{model::Model, session::data_dir};
+
+/// モデル選択の履歴とお気に入り
+///
+/// `<data_dir>/preferences.json` に保存する。
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ModelPreferences {
+    /// 最後に使ったモデル。モデル選択の画面で最初にカーソルを合わせる
+    #[serde(default)]
+    pub last_used: Option<Model>,
+    /// お気に入りのモデル。モデル選択の画面で先頭に表示する
+    #[serde(default)]
+    pub favorites: Vec<Model>,
+}
+
+impl ModelPreferences {
+    /// 保存された設定を読み込む。存在しない、または壊れている場合は空の設定を返す。
+    pub fn load() -> Self {
+        path()
+            .ok()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = path()?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("failed to write {}", path.display()))
+    }
+
+    pub fn is_favorite(&self, model: &Model) -> bool {
+        self.favorites.iter().any(|f| same_model(f, model))
+    }
+
+    pub fn is_last_used(&self, model: &Model) -> bool {
+        self.last_used
+            .as_ref()
+            .is_some_and(|m| same_model(m, model))
+    }
+
+    /// お気に入りに追加、または解除する。追加した場合は `true` を返す。
+    pub fn toggle_favorite(&mut self, model: &Model) -> bool {
+        if self.is_favorite(model) {
+            self.favorites.retain(|f| !same_model(f, model));
+            false
+        } else {
+            self.favorites.push(model.clone());
+            true
+        }
+    }
+
+    /// 最後に使ったモデルを記録して保存する
+    pub fn record_last_used(model: &Model) -> Result<()> {
+        let mut preferences = Self::load();
+        preferences.last_used = Some(model.clone());
+        preferences.save()
+    }
+}
+
+fn same_model(a: &Model, b: &Model) -> bool {
+    a.name == b.name && a.campany == b.campany && a.provider == b.provider
+}
+
+fn path() -> Result<PathBuf> {
+    Ok(data_dir()?.join("preferences.json"))
+}
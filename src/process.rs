@@ -0,0 +1,41 @@
+use std::process::Command;
+
+use anyhow::{anyhow, Context, Result};
+
+/// 設定ファイルで指定されたコマンドをシェルで実行し、標準出力の内容を返す
+///
+/// Azure OpenAIの `token_command` や、AWSの `credential_process` で使う。
+/// 例: `az account get-access-token --resource https://cognitiveservices.azure.com --query accessToken -o tsv`
+pub fn run_command(command: &str) -> Result<String> {
+    let output = if cfg!(windows) {
+        Command::new("cmd").args(["/C", command]).output()
+    } else {
+        Command::new("sh").args(["-c", command]).output()
+    }
+    .with_context(|| format!("failed to run command: {}", command))?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "コマンドが失敗しました（{}）: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let stdout = String::from_utf8(output.stdout)?.trim().to_string();
+    if stdout.is_empty() {
+        return Err(anyhow!("コマンドが何も出力しませんでした: {}", command));
+    }
+    Ok(stdout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_shell_command() {
+        assert_eq!(run_command("echo ' token-123 '").unwrap(), "token-123");
+        assert!(run_command("exit 1").is_err());
+        assert!(run_command("true").is_err());
+    }
+}
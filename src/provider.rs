@@ -0,0 +1,357 @@
+use std::fmt;
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    api_error::ApiErrorKind,
+    chat_message::MessageHistory,
+    http::{self, RetryNotice},
+    model::{Campany, Model},
+    params::GenerationParams,
+    usage::Usage,
+};
+
+/// ストリーミング中にProviderから通知されるイベント
+#[derive(Debug, Clone)]
+pub enum StreamEvent {
+    /// 回答テキストの断片
+    Text(String),
+    /// 回答の生成に消費したトークン数。回答の最後に1回だけ通知する
+    Usage(Usage),
+    /// レート制限などで送信をやり直す。回答を受信し始める前にだけ通知する
+    Retry(RetryNotice),
+    /// 再試行しても送信できなかったので、次のモデルで送り直す
+    Fallback(FallbackNotice),
+    /// 手元にないモデルをダウンロードしている
+    Pull(PullProgress),
+}
+
+/// 代わりのモデルで送り直すときにUIへ通知する内容
+#[derive(Debug, Clone)]
+pub struct FallbackNotice {
+    pub from: Model,
+    pub to: Model,
+    /// 送信できなかった理由
+    pub reason: String,
+}
+
+impl fmt::Display for FallbackNotice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}で送信できなかったため、{}で送り直します（{}）",
+            self.from.name, self.to.name, self.reason
+        )
+    }
+}
+
+/// モデルをダウンロードしているときにUIへ通知する内容
+///
+/// 今のところ、手元にないモデルをダウンロードするのはOllamaだけ。
+#[derive(Debug, Clone, PartialEq)]
+pub struct PullProgress {
+    pub model: String,
+    /// Ollamaが返す段階の名前（`pulling manifest` など）
+    pub status: String,
+    /// ダウンロード済みの割合（0〜100）。ダウンロード以外の段階では `None`
+    pub percent: Option<u64>,
+}
+
+impl PullProgress {
+    /// ダウンロードが完了したか
+    pub fn is_done(&self) -> bool {
+        self.status == "success"
+    }
+}
+
+impl fmt::Display for PullProgress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}をダウンロードしています: {}", self.model, self.status)?;
+        if let Some(percent) = self.percent {
+            write!(f, " {}%", percent)?;
+        }
+        Ok(())
+    }
+}
+
+/// チャットAPIを提供するベンダーの共通インターフェース
+///
+/// ChatGPT/Claudeなど、ベンダーごとのクライアントはこのtraitを実装する。
+/// REPLやコマンドはこのtraitだけに依存するので、新しいベンダーを追加する場合もtraitを実装するだけで良い。
+pub trait ChatProvider {
+    /// ベンダーの種類
+    fn campany(&self) -> Campany;
+
+    /// 設定ファイルの `[providers.<name>]` で追加した接続先の名前。組み込みの接続先の場合は `None`
+    fn instance(&self) -> Option<&str> {
+        None
+    }
+
+    /// `model` をこのProviderで送信できるか
+    fn serves(&self, model: &Model) -> bool {
+        self.campany() == model.campany && self.instance() == model.provider.as_deref()
+    }
+
+    /// 利用可能なモデルの一覧を取得する
+    fn list_models(&self) -> Result<Vec<Model>>;
+
+    /// 利用するモデルをセットする
+    fn set_model(&mut self, model: Model);
+
+    /// 現在選択されているモデル
+    fn model(&self) -> Option<&Model>;
+
+    /// メッセージ履歴をAPIに送信し、回答全体を返す
+    ///
+    /// `params` はベンダーごとのbodyの形に変換して送信する。
+    /// ストリーミング中は、受信したテキストの断片を `on_event` に逐次通知する。
+    fn send_messages(
+        &self,
+        message_history: &MessageHistory,
+        params: &GenerationParams,
+        on_event: &mut dyn FnMut(StreamEvent),
+    ) -> Result<String>;
+}
+
+/// メッセージ履歴を `chain` の先頭のモデルに送信し、失敗したら次のモデルで送り直す
+///
+/// 送り直すのは、レート制限や過負荷などで再試行しても送信できなかった場合だけ。
+/// 回答の一部を受け取った後のエラーでは、回答が重複しないように送り直さない。
+/// 有効になっていないベンダーのモデルや、`params` を送信できないモデルは飛ばす。
+/// 各モデルへ送信する前に `before_send` を呼び、エラーを返した場合はそのモデルへ送信せずにエラーを返す。
+/// 回答と、実際に回答したモデルを返す。
+pub fn send_with_fallback(
+    providers: &mut [Box<dyn ChatProvider>],
+    chain: &[Model],
+    message_history: &MessageHistory,
+    params: &GenerationParams,
+    before_send: &mut dyn FnMut(&Model) -> Result<()>,
+    on_event: &mut dyn FnMut(StreamEvent),
+) -> Result<(Model, String)> {
+    let (first, rest) = chain
+        .split_first()
+        .ok_or_else(|| anyhow!("モデルが選択されていません"))?;
+    let mut rest = rest.iter();
+    let mut model = first.clone();
+    loop {
+        before_send(&model)?;
+        let mut answered = false;
+        let result = send_with_model(providers, &model, message_history, params, &mut |event| {
+            if matches!(event, StreamEvent::Text(_)) {
+                answered = true;
+            }
+            on_event(event)
+        });
+        let err = match result {
+            Ok(answer) => return Ok((model, answer)),
+            Err(err) => err,
+        };
+        if answered || !http::is_retryable(&err) {
+            return Err(err);
+        }
+
+        let usable =
+            |m: &&Model| params.validate(m).is_ok() && providers.iter().any(|p| p.serves(m));
+        let Some(next) = rest.find(usable) else {
+            return Err(err);
+        };
+        on_event(StreamEvent::Fallback(FallbackNotice {
+            from: model,
+            to: next.clone(),
+            reason: ApiErrorKind::classify(&err).to_string(),
+        }));
+        model = next.clone();
+    }
+}
+
+// 一時的にモデルを切り替えて送信し、終わったら元のモデルに戻す
+fn send_with_model(
+    providers: &mut [Box<dyn ChatProvider>],
+    model: &Model,
+    message_history: &MessageHistory,
+    params: &GenerationParams,
+    on_event: &mut dyn FnMut(StreamEvent),
+) -> Result<String> {
+    let provider = providers
+        .iter_mut()
+        .find(|p| p.serves(model))
+        .ok_or_else(|| model_not_available(model))?;
+    let previous = provider.model().cloned();
+    provider.set_model(model.clone());
+    let result = provider.send_messages(message_history, params, on_event);
+    if let Some(previous) = previous {
+        provider.set_model(previous);
+    }
+    result
+}
+
+/// 有効になっていないベンダーのモデルを使おうとした場合のエラー
+pub fn provider_not_enabled(campany: Campany) -> anyhow::Error {
+    anyhow!(
+        "{}は有効になっていません。利用するには環境変数{}をセットしてください",
+        campany,
+        campany.api_key_env()
+    )
+}
+
+/// `model` を送信できるProviderがない場合のエラー
+pub fn model_not_available(model: &Model) -> anyhow::Error {
+    match &model.provider {
+        Some(provider) => anyhow!(
+            "接続先{}は有効になっていません。設定ファイルの [providers.{}] を確認してください",
+            provider,
+            provider
+        ),
+        None => provider_not_enabled(model.campany),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::StatusCode;
+
+    use super::*;
+    use crate::api_error::ApiError;
+
+    // 指定したステータスで失敗するか、モデル名を回答するだけのProvider
+    struct FakeProvider {
+        campany: Campany,
+        model: Option<Model>,
+        fail_with: Option<u16>,
+        /// 失敗する前に回答の一部を送ってくるか
+        partial: bool,
+    }
+
+    impl ChatProvider for FakeProvider {
+        fn campany(&self) -> Campany {
+            self.campany
+        }
+
+        fn list_models(&self) -> Result<Vec<Model>> {
+            Ok(Vec::new())
+        }
+
+        fn set_model(&mut self, model: Model) {
+            self.model = Some(model);
+        }
+
+        fn model(&self) -> Option<&Model> {
+            self.model.as_ref()
+        }
+
+        fn send_messages(
+            &self,
+            _: &MessageHistory,
+            _: &GenerationParams,
+            on_event: &mut dyn FnMut(StreamEvent),
+        ) -> Result<String> {
+            if self.partial {
+                on_event(StreamEvent::Text("途中まで".to_string()));
+            }
+            match self.fail_with {
+                Some(status) => {
+                    Err(ApiError::new(StatusCode::from_u16(status).unwrap(), String::new()).into())
+                }
+                None => Ok(self.model.as_ref().unwrap().name.clone()),
+            }
+        }
+    }
+
+    #[test]
+    fn fall_back_to_next_model() {
+        let claude = Model::from_name("claude-sonnet-4-5");
+        let gpt = Model::from_name("gpt-4o");
+        let provider = |campany, fail_with| -> Box<dyn ChatProvider> {
+            Box::new(FakeProvider {
+                campany,
+                model: None,
+                fail_with,
+                partial: false,
+            })
+        };
+        let history = MessageHistory::default();
+        let params = GenerationParams::default();
+
+        let mut providers = vec![
+            provider(Campany::Claude, Some(529)),
+            provider(Campany::OpenAI, None),
+        ];
+        let mut notices = Vec::new();
+        let (model, answer) = send_with_fallback(
+            &mut providers,
+            &[claude.clone(), gpt.clone()],
+            &history,
+            &params,
+            &mut |_| Ok(()),
+            &mut |event| {
+                if let StreamEvent::Fallback(notice) = event {
+                    notices.push(notice.to.name);
+                }
+            },
+        )
+        .unwrap();
+        assert_eq!(model.name, "gpt-4o");
+        assert_eq!(answer, "gpt-4o");
+        assert_eq!(notices, vec!["gpt-4o"]);
+
+        // 認証エラーなど、送り直しても解決しないエラーでは送り直さない
+        let mut providers = vec![
+            provider(Campany::Claude, Some(401)),
+            provider(Campany::OpenAI, None),
+        ];
+        let result = send_with_fallback(
+            &mut providers,
+            &[claude.clone(), gpt.clone()],
+            &history,
+            &params,
+            &mut |_| Ok(()),
+            &mut |_| {},
+        );
+        assert!(result.is_err());
+
+        // 回答の途中でレート制限になった場合も、回答が重複しないように送り直さない
+        let mut providers: Vec<Box<dyn ChatProvider>> = vec![
+            Box::new(FakeProvider {
+                campany: Campany::Claude,
+                model: None,
+                fail_with: Some(429),
+                partial: true,
+            }),
+            provider(Campany::OpenAI, None),
+        ];
+        let mut fell_back = false;
+        let result = send_with_fallback(
+            &mut providers,
+            &[claude.clone(), gpt.clone()],
+            &history,
+            &params,
+            &mut |_| Ok(()),
+            &mut |event| fell_back |= matches!(event, StreamEvent::Fallback(_)),
+        );
+        assert!(result.is_err());
+        assert!(!fell_back);
+
+        // 送り直す前にも確認し、予算の上限に達するモデルには送信しない
+        let mut providers = vec![
+            provider(Campany::Claude, Some(529)),
+            provider(Campany::OpenAI, None),
+        ];
+        let mut checked = Vec::new();
+        let result = send_with_fallback(
+            &mut providers,
+            &[claude, gpt],
+            &history,
+            &params,
+            &mut |model| {
+                checked.push(model.name.clone());
+                if model.campany == Campany::OpenAI {
+                    return Err(anyhow!("予算の上限に達したため送信しません"));
+                }
+                Ok(())
+            },
+            &mut |_| {},
+        );
+        assert!(result.unwrap_err().to_string().contains("予算"));
+        assert_eq!(checked, vec!["claude-sonnet-4-5", "gpt-4o"]);
+    }
+}
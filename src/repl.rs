@@ -0,0 +1,751 @@
+use std::io::{stdout, Write};
+
+use anyhow::{anyhow, Result};
+use chrono::Local;
+use requestty::Question;
+
+use crate::{
+    api_error::ApiErrorKind,
+    budget::{estimate_request, Budget, Spent},
+    cancel::{self, Cancelled},
+    chat_input::ChatInput,
+    chat_message::{MessageHistory, Role},
+    command::{Command, COMMANDS},
+    context::{self, ContextConfig, TrimStrategy},
+    model::{registry, Campany, Model},
+    params::GenerationParams,
+    picker,
+    preferences::ModelPreferences,
+    provider::{model_not_available, send_with_fallback, ChatProvider, StreamEvent},
+    session::{Session, SessionStore},
+    usage::{self, Usage, UsageLedger, UsageRecord},
+};
+
+/// 対話型でAIと会話するためのREPL
+///
+/// どのベンダーのクライアントも `ChatProvider` として同じループで扱う。
+/// 会話の途中でモデルを切り替えた場合も、同じメッセージ履歴を別のベンダーのモデルで続けられる。
+/// 会話はターンごとに `SessionStore` へ保存され、後から再開できる。
+pub struct Repl {
+    providers: Vec<Box<dyn ChatProvider>>,
+    active: usize,
+    session: Session,
+    store: SessionStore,
+    ledger: UsageLedger,
+    budget: Budget,
+    /// 予算の上限に達していても送信する
+    force: bool,
+    /// 送信できなかった場合に順に送り直すモデル
+    fallbacks: Vec<Model>,
+    context: ContextConfig,
+    input: ChatInput,
+}
+
+impl Repl {
+    pub fn new(
+        providers: Vec<Box<dyn ChatProvider>>,
+        model: Model,
+        session: Session,
+        store: SessionStore,
+    ) -> Result<Self> {
+        let mut repl = Self {
+            providers,
+            active: 0,
+            session,
+            store,
+            ledger: UsageLedger::open_default()?,
+            budget: Budget::default(),
+            force: false,
+            fallbacks: Vec::new(),
+            context: ContextConfig::default(),
+            input: ChatInput::new()?,
+        };
+        repl.switch_model(model)?;
+        Ok(repl)
+    }
+
+    /// 送信前に確認する予算を設定する
+    pub fn with_budget(mut self, budget: Budget, force: bool) -> Self {
+        self.budget = budget;
+        self.force = force;
+        self
+    }
+
+    /// 送信前に履歴をコンテキストウィンドウに収める方法を設定する
+    pub fn with_context(mut self, context: ContextConfig) -> Self {
+        self.context = context;
+        self
+    }
+
+    /// 送信できなかった場合に順に送り直すモデルを設定する
+    pub fn with_fallbacks(mut self, fallbacks: Vec<Model>) -> Self {
+        self.fallbacks = fallbacks;
+        self
+    }
+
+    // 現在利用しているProvider
+    fn provider(&self) -> &dyn ChatProvider {
+        self.providers[self.active].as_ref()
+    }
+
+    /// 利用するモデルを切り替える。モデルのベンダーが異なる場合はProviderも切り替える。
+    pub fn switch_model(&mut self, model: Model) -> Result<()> {
+        let index = self
+            .providers
+            .iter()
+            .position(|p| p.serves(&model))
+            .ok_or_else(|| model_not_available(&model))?;
+        // 次回のモデル選択で最初にカーソルを合わせるために記録する
+        if let Err(e) = ModelPreferences::record_last_used(&model) {
+            eprintln!("⚠️ モデルの選択を記録できませんでした: {:#}", e);
+        }
+        self.providers[index].set_model(model);
+        self.active = index;
+        Ok(())
+    }
+
+    pub fn run(&mut self) -> Result<()> {
+        // 再開したセッションであれば、これまでの会話を表示する
+        if !self.session.history.messages.is_empty() {
+            print_history(&self.session.history);
+        }
+        println!("💾 セッション: {}", self.session.name);
+
+        // 回答の途中のCtrl+Cは、終了せずに回答の中断として扱う
+        cancel::install_handler()?;
+
+        // ユーザーからの質問を無限ループで受け付ける
+        loop {
+            // ユーザーからの入力を受け付ける
+            println!(
+                "👤 質問を入力してください。（入力完了時は改行してCtrl+D、/helpでコマンド一覧）>"
+            );
+            let Some(message) = self.input.read()? else {
+                // 空のプロンプトでCtrl+Cが押されたら終了する
+                return Ok(());
+            };
+
+            // スラッシュコマンドはAPIへ送信せずに実行する
+            if let Some(command) = Command::parse(&message) {
+                if let Err(e) = command.and_then(|c| self.execute(c)) {
+                    eprintln!("⚠️ {:#}", e);
+                }
+                println!();
+                continue;
+            }
+
+            // 空の入力は送信しない
+            if message.trim().is_empty() {
+                continue;
+            }
+
+            // 入力した質問を履歴に追加
+            self.session.history.push(Role::User, &message);
+            self.send_turn(true)?;
+
+            // 次の質問との間に空行を入れる
+            println!();
+        }
+    }
+
+    // 履歴をAPIへ送信して回答を表示し、セッションを保存する
+    //
+    // `new_turn` は末尾の質問をこの送信のために追加した場合に `true`。
+    // `/retry` で既存の質問を送り直す場合は、中断や失敗をしても質問を取り消さない。
+    fn send_turn(&mut self, new_turn: bool) -> Result<()> {
+        cancel::reset();
+        let prepared = self.prepare_history();
+
+        let primary = self.provider().model().cloned();
+        let mut model_name = primary.as_ref().map(|m| m.name.clone()).unwrap_or_default();
+        let vendor = primary
+            .as_ref()
+            .map(|m| m.vendor())
+            .unwrap_or_else(|| self.provider().campany().to_string());
+        println!("🤖 {}からの回答 ({}) >", vendor, model_name);
+
+        // 送信できなかった場合は、プロファイルのfallbackのモデルで順に送り直す
+        let chain: Vec<Model> = primary
+            .iter()
+            .chain(self.fallbacks.iter().filter(|m| {
+                primary.as_ref().map(|p| (&p.name, &p.provider)) != Some((&m.name, &m.provider))
+            }))
+            .cloned()
+            .collect();
+
+        let mut printer = StreamPrinter::default();
+        let mut usage = None;
+        // 中断した場合に履歴へ残すため、届いた回答を保持しておく
+        let mut partial = String::new();
+        let result = prepared
+            .and_then(|history| {
+                let model = primary
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("モデルが選択されていません"))?;
+                self.session.params.validate(model)?;
+                Ok((history, self.load_spent()?))
+            })
+            .and_then(|(history, spent)| {
+                let (budget, force) = (&self.budget, self.force);
+                send_with_fallback(
+                    &mut self.providers,
+                    &chain,
+                    &history,
+                    &self.session.params,
+                    // フォールバック先のモデルでも、送信前に予算を確認する
+                    &mut |model| check_budget(budget, spent.as_ref(), model, &history, force),
+                    &mut |event| match event {
+                        StreamEvent::Text(text) => {
+                            partial.push_str(&text);
+                            printer.handle(StreamEvent::Text(text));
+                        }
+                        StreamEvent::Usage(u) => usage = Some(u),
+                        StreamEvent::Fallback(notice) => {
+                            model_name = notice.to.name.clone();
+                            printer.handle(StreamEvent::Fallback(notice));
+                        }
+                        event => printer.handle(event),
+                    },
+                )
+            });
+        println!();
+
+        match result {
+            Ok((model, assistant_response)) => {
+                self.session
+                    .history
+                    .push_assistant(&assistant_response, &model.name);
+                if let Some(usage) = usage {
+                    self.record_usage(model, usage);
+                }
+            }
+            Err(e) if e.is::<Cancelled>() => {
+                let message =
+                    record_cancelled(&mut self.session.history, &partial, &model_name, new_turn);
+                println!("⏹️ {}", message);
+            }
+            Err(e) => {
+                // エラー時はexitせず、エラー内容を表示してループを継続する
+                self.handle_send_error(&e, new_turn)?;
+            }
+        }
+
+        // ターンごとにセッションを保存する
+        self.save_session();
+        Ok(())
+    }
+
+    // コンテキストウィンドウに収まるように、送信する履歴を決める
+    //
+    // `summarize` の場合は、収まらない古いターンを要約に置き換えてから削る。
+    fn prepare_history(&mut self) -> Result<MessageHistory> {
+        let model = self
+            .provider()
+            .model()
+            .cloned()
+            .ok_or_else(|| anyhow!("モデルが選択されていません"))?;
+        let tokenizer = model.capabilities().tokenizer;
+        let limit = self.context.token_limit(&model, &self.session.params);
+        let strategy = self.context.strategy();
+
+        if strategy == TrimStrategy::Summarize {
+            let split = context::split_point(
+                &self.session.history,
+                tokenizer,
+                limit,
+                TrimStrategy::Summarize,
+                0,
+            );
+            if split > 0 {
+                self.summarize(split)?;
+            }
+        }
+
+        let trimmed = context::trim_history(
+            &self.session.history,
+            tokenizer,
+            limit,
+            strategy,
+            self.context.keep_last(),
+        );
+        if trimmed.dropped > 0 {
+            println!(
+                "✂️ コンテキストウィンドウに収めるため、古いメッセージ{}件を送信しません",
+                trimmed.dropped
+            );
+        }
+        Ok(trimmed.history)
+    }
+
+    // 会話の先頭から `count` 件を要約用のモデルで要約し、履歴を置き換える
+    fn summarize(&mut self, count: usize) -> Result<()> {
+        let enabled: Vec<Campany> = self.providers.iter().map(|p| p.campany()).collect();
+        let summary_model = registry().resolve(self.context.summary_model(), &enabled);
+        if !self.providers.iter().any(|p| p.serves(&summary_model)) {
+            return Err(model_not_available(&summary_model));
+        }
+        println!(
+            "🗜️ 古いメッセージ{}件を{}で要約しています...",
+            count, summary_model.name
+        );
+
+        let request = context::summary_request(&self.session.history, count);
+        let spent = self.load_spent()?;
+        let (summary, usage) = send_summary(
+            &mut self.providers,
+            &summary_model,
+            &request,
+            &self.budget,
+            spent.as_ref(),
+            self.force,
+        )?;
+        context::replace_with_summary(&mut self.session.history, count, &summary);
+        if let Some(usage) = usage {
+            self.record_usage(summary_model, usage);
+        }
+        Ok(())
+    }
+
+    // 予算を設定している場合は、これまでに使った量を台帳から集計する
+    fn load_spent(&self) -> Result<Option<Spent>> {
+        if self.budget.is_empty() {
+            return Ok(None);
+        }
+        Spent::load(&self.ledger, self.session.usage).map(Some)
+    }
+
+    // 1回分のトークン数と料金をセッションと台帳に記録し、フッターを表示する
+    fn record_usage(&mut self, model: Model, usage: Usage) {
+        let cost = usage.cost(&model);
+        self.session.usage.add(usage, cost);
+        println!("{}", usage::footer(&usage, cost, &self.session.usage));
+
+        let record = UsageRecord {
+            timestamp: Local::now(),
+            session: Some(self.session.name.clone()),
+            model,
+            usage,
+            cost,
+        };
+        if let Err(e) = self.ledger.append(&record) {
+            eprintln!("⚠️ 利用量を記録できませんでした: {:#}", e);
+        }
+    }
+
+    // 会話履歴のトークン数を、現在のモデルのトークナイザーで数えて表示する
+    fn print_tokens(&self) -> Result<()> {
+        let model = self
+            .provider()
+            .model()
+            .ok_or_else(|| anyhow!("モデルが選択されていません"))?;
+        let tokenizer = model.capabilities().tokenizer;
+        let history = &self.session.history;
+        println!(
+            "🔢 会話履歴: {}トークン（{}件のメッセージ、{}）",
+            usage::format_number(tokenizer.count_history(history) as u64),
+            history.messages.len(),
+            tokenizer
+        );
+        if let Some(last) = history.messages.last() {
+            println!(
+                "  最後のメッセージ: {}トークン",
+                usage::format_number(tokenizer.count(&last.content) as u64)
+            );
+        }
+        Ok(())
+    }
+
+    // コンテキストウィンドウの使用状況を表示する
+    fn print_context(&self) -> Result<()> {
+        let model = self
+            .provider()
+            .model()
+            .ok_or_else(|| anyhow!("モデルが選択されていません"))?;
+        let capabilities = model.capabilities();
+        let params = &self.session.params;
+        let limit = self.context.token_limit(model, params);
+        let strategy = self.context.strategy();
+        let split = context::split_point(
+            &self.session.history,
+            capabilities.tokenizer,
+            limit,
+            strategy,
+            self.context.keep_last(),
+        );
+
+        println!("🧠 コンテキスト ({})", model.name);
+        println!(
+            "  履歴: {}トークン / 上限 {}トークン（コンテキストウィンドウ {}、回答用に {} を確保）",
+            usage::format_number(capabilities.tokenizer.count_history(&self.session.history) as u64),
+            usage::format_number(limit as u64),
+            usage::format_number(capabilities.context_window as u64),
+            usage::format_number(self.context.reserve_tokens(model, params) as u64),
+        );
+        match strategy {
+            TrimStrategy::KeepLast => {
+                println!("  戦略: {}、{}ターン", strategy, self.context.keep_last())
+            }
+            TrimStrategy::Summarize => {
+                println!("  戦略: {}、{}", strategy, self.context.summary_model())
+            }
+            TrimStrategy::DropOldest => println!("  戦略: {}", strategy),
+        }
+        if split > 0 {
+            println!("  次の送信で古いメッセージ{}件を省略します", split);
+        }
+        Ok(())
+    }
+
+    fn save_session(&mut self) {
+        self.session.model = self.provider().model().cloned();
+        if let Err(e) = self.store.save(&mut self.session) {
+            eprintln!("⚠️ セッションを保存できませんでした: {:#}", e);
+        }
+    }
+
+    // スラッシュコマンドを実行する
+    fn execute(&mut self, command: Command) -> Result<()> {
+        match command {
+            Command::Model(name) => {
+                let model = match name {
+                    Some(name) => {
+                        let enabled: Vec<Campany> =
+                            self.providers.iter().map(|p| p.campany()).collect();
+                        registry().resolve(&name, &enabled)
+                    }
+                    None => picker::select_model_input(&self.providers)?,
+                };
+                if let Err(e) = self.session.params.validate(&model) {
+                    eprintln!("⚠️ {:#} (/setで変更してください)", e);
+                }
+                println!("🤖 モデルを{} ({})に切り替えました", model, model.vendor());
+                self.switch_model(model)?;
+                self.save_session();
+            }
+            Command::Favorite => {
+                let model = self
+                    .provider()
+                    .model()
+                    .cloned()
+                    .ok_or_else(|| anyhow!("モデルが選択されていません"))?;
+                let mut preferences = ModelPreferences::load();
+                if preferences.toggle_favorite(&model) {
+                    println!("⭐ {}をお気に入りに追加しました", model);
+                } else {
+                    println!("⭐ {}をお気に入りから外しました", model);
+                }
+                preferences.save()?;
+            }
+            Command::System(None) => match self.session.history.system() {
+                Some(system) => println!("⚙️ {}", system),
+                None => println!("⚙️ システムプロンプトは設定されていません"),
+            },
+            Command::System(Some(system)) => {
+                self.session.history.set_system(&system);
+                println!("⚙️ システムプロンプトを設定しました");
+                self.save_session();
+            }
+            Command::Clear => {
+                self.session.history.clear();
+                println!("🧹 会話履歴を消去しました");
+                self.save_session();
+            }
+            Command::Save(name) => {
+                if let Some(name) = name {
+                    self.session.name = name;
+                }
+                self.save_session();
+                println!("💾 セッション{}を保存しました", self.session.name);
+            }
+            Command::Load(name) => {
+                let session = match name {
+                    Some(name) => self.store.load(&name)?,
+                    None => picker::select_session_input(&self.store)?,
+                };
+                // セッションで使っていたモデルに切り替える
+                if let Some(model) = session.model.clone() {
+                    self.switch_model(model)?;
+                }
+                self.session = session;
+                print_history(&self.session.history);
+                println!("💾 セッション{}を読み込みました", self.session.name);
+            }
+            Command::Retry => {
+                self.session.history.pop_last_assistant();
+                if !matches!(
+                    self.session.history.messages.last(),
+                    Some(m) if m.role == Role::User
+                ) {
+                    return Err(anyhow!("再送信する質問がありません"));
+                }
+                self.send_turn(false)?;
+            }
+            Command::Undo => {
+                let removed = self.session.history.undo();
+                println!("↩️ {}件のメッセージを取り消しました", removed);
+                self.save_session();
+            }
+            Command::History => print_history(&self.session.history),
+            Command::Tokens => self.print_tokens()?,
+            Command::Context => self.print_context()?,
+            Command::Set(None) => println!("{}", self.session.params),
+            Command::Set(Some(arg)) => {
+                let (name, value) = arg
+                    .split_once(char::is_whitespace)
+                    .ok_or_else(|| anyhow!("usage: /set <name> <value>"))?;
+                let mut params = self.session.params.clone();
+                params.set(name, value)?;
+                if let Some(model) = self.provider().model() {
+                    params.validate(model)?;
+                }
+                self.session.params = params;
+                println!("⚙️ {}を{}に設定しました", name, value.trim());
+                self.save_session();
+            }
+            Command::Help => {
+                for (name, description) in COMMANDS {
+                    println!("  {:<10} {}", name, description);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // 送信に失敗した場合に、エラー内容を表示し、送信できなかった質問を履歴に残すかをユーザーに選ばせる
+    //
+    // `/retry` で送り直した質問（`new_turn` が `false`）は元からある質問なので、常に残す。
+    fn handle_send_error(&mut self, err: &anyhow::Error, new_turn: bool) -> Result<()> {
+        let kind = ApiErrorKind::classify(err);
+        eprintln!("⚠️ {}: {:#}", kind, err);
+        if let Some(hint) = error_hint(kind) {
+            eprintln!("   {}", hint);
+        }
+        if !new_turn {
+            return Ok(());
+        }
+
+        let keep = Question::confirm("keep")
+            .message("送信できなかった質問を履歴に残しますか？")
+            .default(false)
+            .build();
+        let keep = requestty::prompt_one(keep)?.as_bool().unwrap_or(false);
+        if !keep {
+            self.session.history.pop_last_user();
+        }
+        Ok(())
+    }
+}
+
+// 再開したセッションのこれまでの会話を表示する
+fn print_history(history: &MessageHistory) {
+    for message in &history.messages {
+        match message.role {
+            Role::User => println!("👤 >"),
+            Role::Assistant => {
+                let truncated = if message.truncated {
+                    " （中断）"
+                } else {
+                    ""
+                };
+                match &message.model {
+                    Some(model) => println!("🤖 ({}){} >", model, truncated),
+                    None => println!("🤖{} >", truncated),
+                }
+            }
+            Role::System => println!("⚙️ >"),
+        }
+        println!("{}", message.content.trim_end());
+        println!();
+    }
+}
+
+// 回答の生成を中断した場合に履歴を整え、表示するメッセージを返す
+//
+// 途中までの回答は中断したことが分かるようにして残す。
+// 回答が届く前に中断した場合は、この送信で追加した質問だけを取り消す。
+fn record_cancelled(
+    history: &mut MessageHistory,
+    partial: &str,
+    model_name: &str,
+    new_turn: bool,
+) -> &'static str {
+    if !partial.is_empty() {
+        history.push_truncated_assistant(partial, model_name);
+        "回答の生成を中断しました"
+    } else if new_turn {
+        history.pop_last_user();
+        "回答の生成を中断し、質問を取り消しました"
+    } else {
+        "回答の生成を中断しました"
+    }
+}
+
+// 送信前に予算を確認し、閾値を超えていれば警告する。上限に達していればエラーを返す。
+fn check_budget(
+    budget: &Budget,
+    spent: Option<&Spent>,
+    model: &Model,
+    history: &MessageHistory,
+    force: bool,
+) -> Result<()> {
+    let Some(spent) = spent else {
+        return Ok(());
+    };
+    let estimate = estimate_request(history, model);
+    for warning in budget.enforce(spent, &estimate, force)? {
+        eprintln!("💸 {}", warning);
+    }
+    Ok(())
+}
+
+// 要約用のモデルに送信し、要約と消費したトークン数を返す
+//
+// 要約も通常の送信と同じく、送信前に予算を確認する。
+fn send_summary(
+    providers: &mut [Box<dyn ChatProvider>],
+    model: &Model,
+    request: &MessageHistory,
+    budget: &Budget,
+    spent: Option<&Spent>,
+    force: bool,
+) -> Result<(String, Option<Usage>)> {
+    let mut usage = None;
+    let (_, summary) = send_with_fallback(
+        providers,
+        std::slice::from_ref(model),
+        request,
+        &GenerationParams::default(),
+        &mut |model| check_budget(budget, spent, model, request, force),
+        &mut |event| {
+            if let StreamEvent::Usage(u) = event {
+                usage = Some(u);
+            }
+        },
+    )?;
+    Ok((summary, usage))
+}
+
+// エラーの種類ごとに、ユーザーが取るべき対応を返す
+fn error_hint(kind: ApiErrorKind) -> Option<&'static str> {
+    match kind {
+        ApiErrorKind::Auth => Some("APIキーが正しく設定されているか確認してください"),
+        ApiErrorKind::RateLimit => Some("しばらく待ってから再度送信してください"),
+        ApiErrorKind::Overloaded => {
+            Some("サーバーが混雑しています。時間をおいて再度送信してください")
+        }
+        ApiErrorKind::Network => Some("ネットワーク接続を確認してください"),
+        ApiErrorKind::BadRequest => Some("モデル名やメッセージの内容を確認してください"),
+        ApiErrorKind::Other => None,
+    }
+}
+
+/// ストリーミングで届いた回答を逐次Printする
+pub struct StreamPrinter {
+    line_length: usize,
+    max_line_length: usize,
+}
+
+impl Default for StreamPrinter {
+    fn default() -> Self {
+        // APIからの回答が横に長い場合は、読みづらいので改行する。
+        // ただ、適切な改行を行うためにはTerminalの幅を取得する必要があるため、
+        // 現状はMAXを仮で設定し、実質的に途中の強制改行が発生しないようにしている。
+        Self {
+            line_length: 0,
+            max_line_length: usize::MAX,
+        }
+    }
+}
+
+impl StreamPrinter {
+    pub fn handle(&mut self, event: StreamEvent) {
+        match event {
+            StreamEvent::Text(text) => self.print_text(&text),
+            StreamEvent::Usage(_) => {}
+            StreamEvent::Retry(notice) => println!("⏳ {}", notice),
+            StreamEvent::Pull(progress) => {
+                // 同じ行を書き換えて進み具合を表示する
+                print!("\r\x1b[K📥 {}", progress);
+                if progress.is_done() {
+                    println!();
+                }
+                stdout().flush().unwrap();
+            }
+            StreamEvent::Fallback(notice) => {
+                // どのモデルの回答かが分かるように、見出しを出し直す
+                println!("↪️ {}", notice);
+                println!("🤖 {}からの回答 ({}) >", notice.to.vendor(), notice.to.name);
+            }
+        }
+    }
+
+    fn print_text(&mut self, text: &str) {
+        // 逐次Printする
+        print!("{}", text);
+
+        // 改行コードが含まれている場合は一度文字数をリセットする
+        // 文中に改行コードが含まれている場合は少しズレるが、気にしない
+        if text.contains('\n') {
+            self.line_length = 0;
+        }
+
+        // 文字数をプラスする。
+        // UTF-8文字としてカウントしたいので、chars().count()を使う。
+        // https://doc.rust-lang.org/std/string/struct.String.html#utf-8
+        self.line_length += text.chars().count();
+
+        // 最大文字数に達した場合は改行する
+        if self.line_length >= self.max_line_length {
+            println!();
+            self.line_length = 0;
+        }
+
+        // 逐次Printしたものを即座に表示する
+        stdout().flush().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cancelled_retry_keeps_question() {
+        let mut history = MessageHistory::default();
+        history.push(Role::User, "question");
+
+        // /retryで送り直した質問は、回答が届く前に中断しても残す
+        record_cancelled(&mut history, "", "gpt-4o", false);
+        assert_eq!(history.messages.len(), 1);
+        assert_eq!(history.messages[0].content, "question");
+
+        record_cancelled(&mut history, "partial", "gpt-4o", false);
+        assert_eq!(history.messages.len(), 2);
+        assert!(history.messages[1].truncated);
+
+        // 新しく入力した質問は取り消す
+        let mut history = MessageHistory::default();
+        history.push(Role::User, "question");
+        record_cancelled(&mut history, "", "gpt-4o", true);
+        assert!(history.messages.is_empty());
+    }
+
+    #[test]
+    fn summary_checks_budget() {
+        let budget: Budget = toml::from_str("session = { tokens = 1000 }").unwrap();
+        let mut spent = Spent::default();
+        spent.session.usage.input_tokens = 1000;
+        let model = Model::from_name("gpt-4o-mini");
+        let mut request = MessageHistory::default();
+        request.push(Role::User, "summarize");
+
+        // 予算の上限に達していれば、要約も送信しない
+        let err =
+            send_summary(&mut [], &model, &request, &budget, Some(&spent), false).unwrap_err();
+        assert!(err.to_string().contains("予算の上限"));
+
+        // --forceの場合は予算では止めず、送信しようとする
+        let err = send_summary(&mut [], &model, &request, &budget, Some(&spent), true).unwrap_err();
+        assert!(!err.to_string().contains("予算の上限"));
+    }
+}
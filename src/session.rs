@@ -0,0 +1,162 @@
+use std::{fs, path::PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    chat_message::MessageHistory, model::Model, params::GenerationParams, usage::UsageTotals,
+};
+
+/// ディスクに保存される会話セッション
+///
+/// メッセージ履歴に加えて、利用していたモデルと作成・更新日時を保持する。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub name: String,
+    pub model: Option<Model>,
+    pub created_at: DateTime<Local>,
+    pub updated_at: DateTime<Local>,
+    pub history: MessageHistory,
+    #[serde(default)]
+    pub params: GenerationParams,
+    /// このセッションで消費したトークン数と料金の合計
+    #[serde(default)]
+    pub usage: UsageTotals,
+}
+
+impl Session {
+    pub fn new(name: &str) -> Self {
+        let now = Local::now();
+        Self {
+            name: name.to_owned(),
+            model: None,
+            created_at: now,
+            updated_at: now,
+            history: MessageHistory::default(),
+            params: GenerationParams::default(),
+            usage: UsageTotals::default(),
+        }
+    }
+
+    /// 作成日時から名前を付けた新しいセッション
+    pub fn new_unnamed() -> Self {
+        Self::new(&Local::now().format("%Y%m%d-%H%M%S").to_string())
+    }
+
+    /// ピッカーに表示するための一行の説明
+    pub fn summary(&self) -> String {
+        let model = self.model.as_ref().map(|m| m.name.as_str()).unwrap_or("-");
+        format!(
+            "{}  ({}, {}件, {})",
+            self.name,
+            model,
+            self.history.messages.len(),
+            self.updated_at.format("%Y-%m-%d %H:%M")
+        )
+    }
+}
+
+/// セッションをJSONファイルとして保存するディレクトリ
+///
+/// デフォルトは `<data_dir>/aichat-cli/sessions`。環境変数 `AICHAT_DATA_DIR` で変更できる。
+pub struct SessionStore {
+    dir: PathBuf,
+}
+
+impl SessionStore {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    pub fn open_default() -> Result<Self> {
+        Ok(Self::new(data_dir()?.join("sessions")))
+    }
+
+    /// セッションを保存する。更新日時は現在時刻になる。
+    pub fn save(&self, session: &mut Session) -> Result<()> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("failed to create {}", self.dir.display()))?;
+        session.updated_at = Local::now();
+        let path = self.path(&session.name)?;
+        let json = serde_json::to_string_pretty(session)?;
+        fs::write(&path, json).with_context(|| format!("failed to write {}", path.display()))?;
+        Ok(())
+    }
+
+    pub fn load(&self, name: &str) -> Result<Session> {
+        let path = self.path(name)?;
+        let json = fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let session = serde_json::from_str(&json)
+            .with_context(|| format!("failed to parse {}", path.display()))?;
+        Ok(session)
+    }
+
+    pub fn exists(&self, name: &str) -> bool {
+        self.path(name).map(|p| p.exists()).unwrap_or(false)
+    }
+
+    /// 保存済みのセッションを更新日時の新しい順に返す
+    ///
+    /// 壊れたファイルは読み飛ばす。
+    pub fn list(&self) -> Result<Vec<Session>> {
+        if !self.dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut sessions: Vec<Session> = fs::read_dir(&self.dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .filter_map(|path| fs::read_to_string(path).ok())
+            .filter_map(|json| serde_json::from_str(&json).ok())
+            .collect();
+        sessions.sort_by_key(|s: &Session| s.updated_at);
+        sessions.reverse();
+        Ok(sessions)
+    }
+
+    // セッション名からファイルパスを作る。ディレクトリの外を指す名前は拒否する。
+    fn path(&self, name: &str) -> Result<PathBuf> {
+        if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+            return Err(anyhow!("invalid session name: {}", name));
+        }
+        Ok(self.dir.join(format!("{}.json", name)))
+    }
+}
+
+/// aichat-cliがデータを保存するディレクトリ
+pub fn data_dir() -> Result<PathBuf> {
+    if let Ok(dir) = std::env::var("AICHAT_DATA_DIR") {
+        return Ok(PathBuf::from(dir));
+    }
+    dirs::data_dir()
+        .map(|dir| dir.join("aichat-cli"))
+        .ok_or_else(|| anyhow!("データディレクトリが見つかりません"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat_message::Role;
+
+    #[test]
+    fn save_and_load_session() {
+        let dir = std::env::temp_dir().join(format!("aichat-cli-test-{}", std::process::id()));
+        let store = SessionStore::new(dir.clone());
+
+        let mut session = Session::new("debug");
+        session.history.push(Role::User, "hello");
+        session.history.push(Role::Assistant, "hi");
+        store.save(&mut session).unwrap();
+
+        let loaded = store.load("debug").unwrap();
+        assert_eq!(loaded.history.messages.len(), 2);
+        assert_eq!(loaded.history.messages[1].role, Role::Assistant);
+        assert_eq!(store.list().unwrap().len(), 1);
+        assert!(store.load("../etc").is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,92 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use tiktoken_rs::{cl100k_base_singleton, o200k_base_singleton};
+
+use crate::chat_message::{Message, MessageHistory};
+
+/// 1件のメッセージにつく、ロールや区切りのためのトークン数
+pub const TOKENS_PER_MESSAGE: usize = 3;
+/// 回答の前に付くトークン数
+pub const TOKENS_PER_REPLY: usize = 3;
+
+// Claudeのトークナイザーは公開されていないため、cl100k_baseで数えた値をこの倍率で補正する
+const CLAUDE_RATIO: f64 = 1.1;
+
+/// トークン数の数え方
+///
+/// OpenAIのモデルはtiktokenと同じBPEの表で正確に数え、Claudeは近似する。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Tokenizer {
+    /// GPT-3.5やGPT-4
+    Cl100kBase,
+    /// GPT-4o以降やo系の推論モデル
+    #[default]
+    O200kBase,
+    /// Claude（cl100k_baseからの近似）
+    Claude,
+}
+
+impl fmt::Display for Tokenizer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Tokenizer::Cl100kBase => write!(f, "cl100k_base"),
+            Tokenizer::O200kBase => write!(f, "o200k_base"),
+            Tokenizer::Claude => write!(f, "Claude（近似）"),
+        }
+    }
+}
+
+impl Tokenizer {
+    /// テキストのトークン数
+    pub fn count(&self, text: &str) -> usize {
+        match self {
+            Tokenizer::Cl100kBase => cl100k_base_singleton().encode_ordinary(text).len(),
+            Tokenizer::O200kBase => o200k_base_singleton().encode_ordinary(text).len(),
+            Tokenizer::Claude => {
+                let tokens = cl100k_base_singleton().encode_ordinary(text).len();
+                (tokens as f64 * CLAUDE_RATIO).ceil() as usize
+            }
+        }
+    }
+
+    /// メッセージ1件のトークン数。ロールなどの分を含む。
+    pub fn count_message(&self, message: &Message) -> usize {
+        self.count(&message.content) + TOKENS_PER_MESSAGE
+    }
+
+    /// 履歴全体を送信したときの入力トークン数
+    pub fn count_history(&self, history: &MessageHistory) -> usize {
+        if history.messages.is_empty() {
+            return 0;
+        }
+        history
+            .messages
+            .iter()
+            .map(|m| self.count_message(m))
+            .sum::<usize>()
+            + TOKENS_PER_REPLY
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat_message::Role;
+
+    #[test]
+    fn count_with_bpe_tables() {
+        assert_eq!(Tokenizer::Cl100kBase.count("hello world"), 2);
+        assert_eq!(Tokenizer::O200kBase.count("hello world"), 2);
+        assert_eq!(Tokenizer::Claude.count("hello world"), 3);
+
+        let mut history = MessageHistory::default();
+        assert_eq!(Tokenizer::O200kBase.count_history(&history), 0);
+        history.push(Role::User, "hello world");
+        assert_eq!(
+            Tokenizer::O200kBase.count_history(&history),
+            2 + TOKENS_PER_MESSAGE + TOKENS_PER_REPLY
+        );
+    }
+}
@@ -0,0 +1,230 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io::Write,
+    ops::AddAssign,
+    path::PathBuf,
+};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Datelike, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::{model::Model, session::data_dir};
+
+/// 1回のリクエストで消費したトークン数
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Usage {
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    /// 入力トークンのうち、キャッシュから読み込まれたもの
+    #[serde(default)]
+    pub cached_tokens: u64,
+}
+
+impl AddAssign for Usage {
+    fn add_assign(&mut self, other: Usage) {
+        self.input_tokens += other.input_tokens;
+        self.output_tokens += other.output_tokens;
+        self.cached_tokens += other.cached_tokens;
+    }
+}
+
+impl Usage {
+    /// モデルレジストリの料金表から料金（USD）を計算する。料金が分からないモデルは `None` を返す。
+    pub fn cost(&self, model: &Model) -> Option<f64> {
+        let pricing = model.capabilities().pricing?;
+        let cached = self.cached_tokens.min(self.input_tokens);
+        let uncached = self.input_tokens - cached;
+        let cached_price = pricing.cached_input.unwrap_or(pricing.input);
+        let cost = uncached as f64 * pricing.input
+            + cached as f64 * cached_price
+            + self.output_tokens as f64 * pricing.output;
+        Some(cost / 1_000_000.0)
+    }
+}
+
+/// トークン数と料金の合計
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct UsageTotals {
+    #[serde(flatten)]
+    pub usage: Usage,
+    pub cost: f64,
+}
+
+impl UsageTotals {
+    pub fn add(&mut self, usage: Usage, cost: Option<f64>) {
+        self.usage += usage;
+        self.cost += cost.unwrap_or(0.0);
+    }
+}
+
+/// 回答の後に表示する、トークン数と料金の短い説明
+///
+/// 例: `📊 入力 1,234 (キャッシュ 1,000) / 出力 567 tokens  $0.0123  (セッション計 $0.0456)`
+pub fn footer(usage: &Usage, cost: Option<f64>, session: &UsageTotals) -> String {
+    let mut footer = format!("📊 入力 {}", format_number(usage.input_tokens));
+    if usage.cached_tokens > 0 {
+        footer.push_str(&format!(
+            " (キャッシュ {})",
+            format_number(usage.cached_tokens)
+        ));
+    }
+    footer.push_str(&format!(
+        " / 出力 {} tokens",
+        format_number(usage.output_tokens)
+    ));
+    if let Some(cost) = cost {
+        footer.push_str(&format!("  ${:.4}", cost));
+    }
+    footer.push_str(&format!("  (セッション計 ${:.4})", session.cost));
+    footer
+}
+
+/// 3桁ごとにカンマで区切る
+pub fn format_number(n: u64) -> String {
+    let digits = n.to_string();
+    let mut formatted = String::new();
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            formatted.push(',');
+        }
+        formatted.push(c);
+    }
+    formatted
+}
+
+/// 台帳に記録される1回分の利用
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UsageRecord {
+    pub timestamp: DateTime<Local>,
+    pub session: Option<String>,
+    pub model: Model,
+    #[serde(flatten)]
+    pub usage: Usage,
+    pub cost: Option<f64>,
+}
+
+/// トークン数と料金を記録する台帳
+///
+/// `<data_dir>/usage.jsonl` に1回の利用ごとに1行のJSONを追記する。
+pub struct UsageLedger {
+    path: PathBuf,
+}
+
+impl UsageLedger {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn open_default() -> Result<Self> {
+        Ok(Self::new(data_dir()?.join("usage.jsonl")))
+    }
+
+    pub fn append(&self, record: &UsageRecord) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("failed to open {}", self.path.display()))?;
+        writeln!(file, "{}", serde_json::to_string(record)?)?;
+        Ok(())
+    }
+
+    /// 記録された全ての利用。壊れた行は読み飛ばす。
+    pub fn records(&self) -> Result<Vec<UsageRecord>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        let text = fs::read_to_string(&self.path)
+            .with_context(|| format!("failed to read {}", self.path.display()))?;
+        Ok(text
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect())
+    }
+
+    /// 指定した年月の利用を、モデルごとに集計する
+    pub fn monthly_summary(&self, year: i32, month: u32) -> Result<BTreeMap<String, UsageTotals>> {
+        let mut summary: BTreeMap<String, UsageTotals> = BTreeMap::new();
+        for record in self.records()? {
+            if record.timestamp.year() == year && record.timestamp.month() == month {
+                summary
+                    .entry(record.model.name.clone())
+                    .or_default()
+                    .add(record.usage, record.cost);
+            }
+        }
+        Ok(summary)
+    }
+
+    /// `usage` サブコマンドで表示する、指定した年月の利用の集計表
+    pub fn monthly_report(&self, year: i32, month: u32) -> Result<String> {
+        let summary = self.monthly_summary(year, month)?;
+        let mut report = format!("📊 {}年{}月の利用\n", year, month);
+        if summary.is_empty() {
+            report.push_str("利用の記録がありません\n");
+            return Ok(report);
+        }
+
+        let mut total = UsageTotals::default();
+        for (model, totals) in &summary {
+            report.push_str(&format_row(model, totals));
+            total.usage += totals.usage;
+            total.cost += totals.cost;
+        }
+        report.push_str(&format_row("合計", &total));
+        Ok(report)
+    }
+}
+
+fn format_row(label: &str, totals: &UsageTotals) -> String {
+    format!(
+        "  {:<34} 入力 {:>12}  出力 {:>12}  ${:.4}\n",
+        label,
+        format_number(totals.usage.input_tokens),
+        format_number(totals.usage.output_tokens),
+        totals.cost
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cost_and_ledger() {
+        let model = Model::from_name("gpt-4o");
+        let usage = Usage {
+            input_tokens: 1_000_000,
+            output_tokens: 100_000,
+            cached_tokens: 500_000,
+        };
+        // 500K * $2.50 + 500K * $1.25 + 100K * $10.00
+        assert!((usage.cost(&model).unwrap() - 2.875).abs() < 1e-9);
+        assert_eq!(format_number(1234567), "1,234,567");
+
+        let path = std::env::temp_dir().join(format!("aichat-usage-{}.jsonl", std::process::id()));
+        let ledger = UsageLedger::new(path.clone());
+        let now = Local::now();
+        for _ in 0..2 {
+            ledger
+                .append(&UsageRecord {
+                    timestamp: now,
+                    session: None,
+                    model: model.clone(),
+                    usage,
+                    cost: usage.cost(&model),
+                })
+                .unwrap();
+        }
+        let summary = ledger.monthly_summary(now.year(), now.month()).unwrap();
+        assert_eq!(summary["gpt-4o"].usage.input_tokens, 2_000_000);
+        assert!((summary["gpt-4o"].cost - 5.75).abs() < 1e-9);
+
+        fs::remove_file(path).unwrap();
+    }
+}